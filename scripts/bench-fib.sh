@@ -0,0 +1,38 @@
+#!/bin/sh
+# Times (fib 25) through the REPL of each given git revision, built in
+# release mode in a temporary worktree, e.g.
+#
+#     scripts/bench-fib.sh 123b750~1 123b750 HEAD
+#
+# Each revision is run with its default settings, so the numbers compare
+# whole interpreters rather than a single backend.
+set -eu
+
+runs=5
+program='(def! fib (fn* (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))'
+root=$(git rev-parse --show-toplevel)
+tmp=$(mktemp -d)
+trap 'git -C "$root" worktree remove --force "$tmp/tree" 2>/dev/null; rm -rf "$tmp"' EXIT
+
+for rev in "$@"; do
+    git -C "$root" worktree add --quiet --detach "$tmp/tree" "$rev"
+    cargo build --quiet --release --manifest-path "$tmp/tree/Cargo.toml" \
+        --target-dir "$tmp/target"
+    input="$program"
+    i=0
+    while [ $i -lt $runs ]; do
+        input="$input
+(fib 25)"
+        i=$((i + 1))
+    done
+    start=$(date +%s.%N)
+    results=$(printf '%s\n' "$input" | "$tmp/target/release/mal" | grep -c 75025)
+    end=$(date +%s.%N)
+    if [ "$results" -ne $runs ]; then
+        echo "$rev: fib(25) did not return 75025" >&2
+        exit 1
+    fi
+    awk -v rev="$rev" -v start="$start" -v end="$end" -v n=$runs \
+        'BEGIN { printf "%s: %.2fs per run\n", rev, (end - start) / n }'
+    git -C "$root" worktree remove --force "$tmp/tree"
+done
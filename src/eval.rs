@@ -1,225 +1,503 @@
 use crate::types::{
-    env::{EnvVal, Environment, EnvironmentBuilder},
-    EvalError, EvalResult, MalAtom, MalFn, MalVal,
+    env::{Backend, EnvVal, Environment, EnvironmentBuilder},
+    literal_map,
+    symbol::Symbol,
+    EvalError, EvalResult, Frame, MalAtom, MalFn, MalVal, Meta, SpecialForm,
 };
-use itertools::Itertools;
+use std::rc::Rc;
 
+use self::{
+    analyze::{analyze, Expr, Form, Lambda},
+    compile::{compile, Chunk},
+    optimize::optimize,
+};
+
+pub mod analyze;
 pub mod builtin;
+pub mod compile;
+pub mod optimize;
+pub mod vm;
 
-pub fn eval(ast: MalVal, env: &Environment) -> EvalResult<MalVal> {
-    match ast {
-        MalVal::List(list) => {
-            if list.is_empty() {
-                Ok(MalVal::List(list))
-            } else if list[0] == MalVal::Atom(MalAtom::Sym("def!".to_owned())) {
-                handle_def(env, list)
-            } else if list[0] == MalVal::Atom(MalAtom::Sym("let*".to_owned())) {
-                handle_let(env, list)
-            } else if list[0] == MalVal::Atom(MalAtom::Sym("fn*".to_owned())) {
-                handle_fn(env, list)
-            } else if list[0] == MalVal::Atom(MalAtom::Sym("do".to_owned())) {
-                handle_do(env, list)
-            } else if list[0] == MalVal::Atom(MalAtom::Sym("if".to_owned())) {
-                handle_if(env, list)
-            } else {
-                let evaluated = eval_ast(MalVal::List(list), env)?;
-
-                if let MalVal::List(mut list) = evaluated {
-                    // TODO: removing the first element of a vector is not great
-                    // as it shuffles all the values left by one
-                    let sym = list.remove(0);
-                    if let MalVal::Atom(MalAtom::Sym(sym_name)) = sym {
-                        apply_native_fn(sym_name, list, env)
-                    } else if let MalVal::Fn(fbox) = sym {
-                        apply_fn(*fbox, list)
-                    } else {
-                        Err(EvalError::BadFunctionDesignator(sym.to_string()))
-                    }
-                } else {
-                    panic!("list evaluated to non list")
+pub fn eval(ast: &MalVal, env: &Environment) -> EvalResult<MalVal> {
+    let mut expr = analyze(ast, env)?;
+    if env.optimize() {
+        expr = optimize(expr, env);
+    }
+    match env.backend() {
+        Backend::TreeWalk => {
+            let _depth = env.budget().descend()?;
+            exec(&expr, env)
+        }
+        Backend::Bytecode => vm::run(Rc::new(compile(&expr)), env),
+    }
+}
+
+/// Evaluates an analyzed form. Every node consumes one step of the budget.
+/// Depth is counted per top-level form and per call to a MAL function, as
+/// in `vm::run`, except that without tail call elimination tail calls,
+/// such as a call that makes up a whole top-level form, count too.
+fn exec(expr: &Expr, env: &Environment) -> EvalResult<MalVal> {
+    env.step()?;
+    // Every nested evaluation recurses through here, so the less common
+    // forms live in their own functions to keep this frame small.
+    match expr {
+        Expr::Const(v) => Ok(v.clone()),
+        Expr::Local { depth, slot } => env
+            .get_local(*depth, *slot)
+            .or_else(|sym| exec_global(sym, env)),
+        Expr::Global(sym) => exec_global(*sym, env),
+        Expr::Vector(items, meta) => exec_vector(items, meta, env),
+        Expr::Map(entries, meta) => exec_map(entries, meta, env),
+        Expr::Set(items, meta) => exec_set(items, meta, env),
+        Expr::Def { sym, slot, value } => exec_def(*sym, *slot, value, env),
+        Expr::Let { names, inits, body } => exec(body, &bind_frame(env, names, inits)?),
+        Expr::Fn(lambda) => Ok(closure(lambda, env, None)),
+        Expr::Do(forms) => match forms.split_last() {
+            Some((last, init)) => {
+                for v in init {
+                    exec(v, env)?;
                 }
+                exec(last, env)
+            }
+            None => Ok(MalVal::Atom(MalAtom::Nil)),
+        },
+        Expr::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            if exec(cond, env)?.is_truthy() {
+                exec(then, env)
+            } else if let Some(otherwise) = otherwise {
+                exec(otherwise, env)
+            } else {
+                Ok(MalVal::Atom(MalAtom::Nil))
             }
         }
-        _ => eval_ast(ast, env),
+        Expr::Loop { names, inits, body } => exec_loop(names, inits, body, env),
+        // A recur in a valid position is consumed by exec_loop_body, so
+        // reaching it here means there is no enclosing loop.
+        Expr::Recur(_) => Err(EvalError::RecurOutsideLoop),
+        Expr::Call { form, head, items } => exec_call(form, *head, items, env),
+        Expr::Special(sym, list) => custom_form(*sym, env)(env, list),
     }
 }
 
-fn apply_native_fn(sym_name: String, args: Vec<MalVal>, env: &Environment) -> EvalResult<MalVal> {
-    if let Some(env_val) = env.get(&sym_name) {
-        if let EnvVal::NativeFn(f) = env_val {
-            Ok(f(args)?)
-        } else {
-            Err(EvalError::BadFunctionDesignator(sym_name))
-        }
-    } else {
-        Err(EvalError::FunctionUndefined(sym_name))
+/// The embedder's special form that `Expr::Special` was analyzed from. The
+/// registry cannot change after the environment is built.
+fn custom_form(sym: Symbol, env: &Environment) -> SpecialForm {
+    match env.special_form(sym) {
+        Some(Form::Custom(form)) => form,
+        form => unreachable!("{} is not a custom special form: {:?}", sym, form),
     }
 }
 
-fn apply_fn(f: MalFn, args: Vec<MalVal>) -> EvalResult<MalVal> {
-    if f.binds.len() != args.len() {
-        return Err(EvalError::InvalidArgs);
+/// Looks up a variable by name. Besides globals this finds locals that
+/// were not in scope when the form was analyzed, such as a `def!` further
+/// down in an enclosing function.
+fn exec_global(sym: Symbol, env: &Environment) -> EvalResult<MalVal> {
+    match env.get(sym) {
+        Some(EnvVal::NativeFn(_)) => Ok(MalVal::Atom(MalAtom::Sym(sym))),
+        Some(EnvVal::Val(v)) => Ok(v),
+        None => Err(EvalError::SymbolNotFound(sym.to_string())),
     }
-    let child_env = EnvironmentBuilder::new().with_parent(&f.env).build();
-    for (s, v) in f.binds.into_iter().zip(args.into_iter()) {
-        child_env.set(s, v);
+}
+
+fn exec_vector(items: &[Expr], meta: &Meta, env: &Environment) -> EvalResult<MalVal> {
+    Ok(MalVal::Vector(
+        items
+            .iter()
+            .map(|v| exec(v, env))
+            .collect::<EvalResult<_>>()?,
+        meta.clone(),
+    ))
+}
+
+fn exec_set(items: &[Expr], meta: &Meta, env: &Environment) -> EvalResult<MalVal> {
+    Ok(MalVal::Set(
+        items
+            .iter()
+            .map(|v| exec(v, env))
+            .collect::<EvalResult<_>>()?,
+        meta.clone(),
+    ))
+}
+
+fn exec_map(entries: &[(Expr, Expr)], meta: &Meta, env: &Environment) -> EvalResult<MalVal> {
+    let entries = entries
+        .iter()
+        .map(|(k, v)| Ok((exec(k, env)?, exec(v, env)?)))
+        .collect::<EvalResult<Vec<_>>>()?;
+    let map = literal_map(entries).map_err(|k| EvalError::DuplicateKey(k.to_string()))?;
+    Ok(MalVal::AssocArray(map, meta.clone()))
+}
+
+fn exec_def(
+    sym: Symbol,
+    slot: Option<usize>,
+    value: &Expr,
+    env: &Environment,
+) -> EvalResult<MalVal> {
+    let mut evaluated = exec(value, env)?;
+    name_fn(&mut evaluated, sym);
+    match slot {
+        Some(slot) => env.set_local(slot, evaluated.clone()),
+        None => env.set(sym, evaluated.clone()),
     }
-    eval(f.body, &child_env)
+    Ok(evaluated)
 }
 
-fn eval_ast(ast: MalVal, env: &Environment) -> EvalResult<MalVal> {
-    match ast {
-        MalVal::Atom(atom) => match atom {
-            MalAtom::Sym(sym) => {
-                if let Some(env_val) = env.get(&sym) {
-                    match env_val {
-                        EnvVal::NativeFn(_) => Ok(MalVal::Atom(MalAtom::Sym(sym))),
-                        EnvVal::Val(v) => Ok(v),
-                    }
-                } else {
-                    Err(EvalError::SymbolNotFound(sym))
-                }
-            }
-            _ => Ok(MalVal::Atom(atom)),
-        },
-        MalVal::List(list) => {
-            let mut evaluated = Vec::new();
-            for v in list.into_iter() {
-                evaluated.push(eval(v, env)?);
+fn exec_loop(
+    names: &Rc<[Symbol]>,
+    inits: &[Expr],
+    body: &Expr,
+    env: &Environment,
+) -> EvalResult<MalVal> {
+    let mut iter_env = bind_frame(env, names, inits)?;
+    loop {
+        match exec_loop_body(body, &iter_env)? {
+            LoopStep::Done(v) => return Ok(v),
+            LoopStep::Recur(args) => {
+                // Each iteration gets a fresh frame so closures created in
+                // previous iterations keep the values they captured.
+                iter_env = EnvironmentBuilder::new()
+                    .with_parent(env)
+                    .with_frame(names.clone(), args)
+                    .build();
             }
-            Ok(MalVal::List(evaluated))
-        }
-        MalVal::Vector(_) => {
-            unimplemented!()
-        }
-        MalVal::AssocArray(_) => {
-            unimplemented!()
-        }
-        MalVal::Fn(_) => {
-            unreachable!()
         }
     }
 }
 
-fn handle_def(env: &Environment, mut list: Vec<MalVal>) -> EvalResult<MalVal> {
-    if list.len() != 3 {
-        return Err(EvalError::InvalidArgs);
-    }
-    list.remove(0);
-    let atom = list.remove(0);
-    if let MalVal::Atom(MalAtom::Sym(sym_name)) = atom {
-        let evaluated = eval(list.remove(0), env)?;
-        env.set(sym_name, evaluated.clone());
-        Ok(evaluated)
+fn exec_call(
+    form: &MalVal,
+    head: Option<Symbol>,
+    items: &[Expr],
+    env: &Environment,
+) -> EvalResult<MalVal> {
+    let mut args = items
+        .iter()
+        .map(|v| exec(v, env))
+        .collect::<EvalResult<Vec<_>>>()?;
+    let f = args.remove(0);
+    if let MalVal::Atom(MalAtom::Sym(sym_name)) = f {
+        apply_native_fn(sym_name, args, env)
+    } else if let MalVal::Fn(f) = f {
+        apply_fn(&f, args, || {
+            let name = f.name.clone().or_else(|| head.map(|h| h.to_string()));
+            Frame::new(name, form.clone())
+        })
     } else {
-        Err(EvalError::NotASymbol)
+        Err(EvalError::BadFunctionDesignator(f.to_string()))
     }
 }
-fn handle_let(env: &Environment, mut list: Vec<MalVal>) -> EvalResult<MalVal> {
-    if list.len() != 3 {
-        return Err(EvalError::InvalidArgs);
-    }
-    list.remove(0);
-    if let MalVal::List(vars) = list.remove(0) {
-        let child_env = EnvironmentBuilder::new().with_parent(env).build();
-        if vars.len() % 2 != 0 {
-            return Err(EvalError::InvalidArgs);
-        }
-        let mut it = vars.into_iter();
-        while let Some((sym, to_eval)) = it.next_tuple() {
-            match sym {
-                MalVal::Atom(MalAtom::Sym(sym_name)) => {
-                    let evaluated = eval(to_eval, &child_env)?;
-                    child_env.set(sym_name, evaluated);
-                }
-                _ => return Err(EvalError::NotASymbol),
-            }
+
+/// Names a function after the symbol it is first defined as.
+fn name_fn(value: &mut MalVal, sym: Symbol) {
+    if let MalVal::Fn(f) = value {
+        if f.name.is_none() {
+            Rc::make_mut(f).name = Some(sym.to_string());
         }
-        let to_eval = list.remove(0);
-        eval(to_eval, &child_env)
-    } else {
-        Err(EvalError::NotAList)
     }
 }
 
-fn handle_fn(env: &Environment, mut list: Vec<MalVal>) -> EvalResult<MalVal> {
-    if list.len() != 3 {
-        return Err(EvalError::InvalidArgs);
-    }
-    list.remove(0);
-    if let MalVal::List(vars) = list.remove(0) {
-        let mut binds = Vec::new();
+fn closure(lambda: &Lambda, env: &Environment, chunk: Option<Rc<Chunk>>) -> MalVal {
+    env.track();
+    MalVal::Fn(Rc::new(MalFn {
+        env: env.clone(),
+        body: lambda.body.clone(),
+        binds: lambda.binds.clone(),
+        names: lambda.names.clone(),
+        code: lambda.code.clone(),
+        chunk,
+        name: None,
+        doc: lambda.doc.clone(),
+        span: lambda.span,
+        meta: Meta::default(),
+    }))
+}
 
-        for v in vars.into_iter() {
-            if let MalVal::Atom(MalAtom::Sym(sym_name)) = v {
-                binds.push(sym_name);
-            } else {
-                return Err(EvalError::NotASymbol);
-            }
+fn apply_native_fn(sym: Symbol, args: Vec<MalVal>, env: &Environment) -> EvalResult<MalVal> {
+    if let Some(env_val) = env.get_global(sym) {
+        if let EnvVal::NativeFn(f) = env_val {
+            Ok(f(args)?)
+        } else {
+            Err(EvalError::BadFunctionDesignator(sym.to_string()))
         }
-        let body = list.remove(0);
-        Ok(MalVal::Fn(Box::new(MalFn {
-            env: env.clone(),
-            body,
-            binds,
-        })))
     } else {
-        Err(EvalError::NotAList)
+        Err(EvalError::FunctionUndefined(sym.to_string()))
     }
 }
 
-fn handle_do(env: &Environment, mut list: Vec<MalVal>) -> EvalResult<MalVal> {
-    list.remove(0);
-    let mut ret = MalVal::Atom(MalAtom::Nil);
-    for v in list.into_iter() {
-        ret = eval(v, env)?;
+/// Applies a function value, as obtained by evaluating the name of a
+/// builtin or a `fn*` form, to already evaluated `args`.
+pub fn apply(f: &MalVal, args: Vec<MalVal>, env: &Environment) -> EvalResult<MalVal> {
+    match f {
+        MalVal::Atom(MalAtom::Sym(sym)) => apply_native_fn(*sym, args, env),
+        MalVal::Fn(f) => {
+            let frame = || {
+                let head = match &f.name {
+                    Some(name) => MalVal::Atom(MalAtom::Sym(name.as_str().into())),
+                    None => MalVal::Fn(f.clone()),
+                };
+                let form = std::iter::once(head).chain(args.clone()).collect();
+                Frame::new(f.name.clone(), MalVal::List(form, Meta::default()))
+            };
+            let child_env = bind_args(f, args.clone())?;
+            let result = match env.backend() {
+                Backend::TreeWalk => exec_body(f, &child_env),
+                Backend::Bytecode => vm::run(vm::chunk(f), &child_env),
+            };
+            result.map_err(|e| e.with_frame(frame()))
+        }
+        f => Err(EvalError::BadFunctionDesignator(f.to_string())),
     }
-    Ok(ret)
 }
 
-fn handle_if(env: &Environment, mut list: Vec<MalVal>) -> EvalResult<MalVal> {
-    if list.len() < 3 {
+/// Creates the call frame of `f` holding `args`.
+fn bind_args(f: &MalFn, args: Vec<MalVal>) -> EvalResult<Environment> {
+    if f.binds.len() != args.len() {
         return Err(EvalError::InvalidArgs);
     }
+    Ok(EnvironmentBuilder::new()
+        .with_parent(&f.env)
+        .with_frame(f.names.clone(), args)
+        .build())
+}
 
-    list.remove(0);
-    let cond = eval(list.remove(0), env)?.is_truthy();
-    let t = list.remove(0);
+/// Applies `f` to `args`. Errors raised while evaluating the body are
+/// annotated with the stack frame produced by `frame`.
+fn apply_fn<F>(f: &MalFn, args: Vec<MalVal>, frame: F) -> EvalResult<MalVal>
+where
+    F: FnOnce() -> Frame,
+{
+    let child_env = bind_args(f, args)?;
+    exec_body(f, &child_env).map_err(|e| e.with_frame(frame()))
+}
 
-    if cond {
-        eval(t, env)
-    } else if list.is_empty() {
-        Ok(MalVal::Atom(MalAtom::Nil))
-    } else {
-        eval(list.remove(0), env)
+/// Evaluates the body of `f` in its call frame `env`, one level deeper.
+fn exec_body(f: &MalFn, env: &Environment) -> EvalResult<MalVal> {
+    let _depth = env.budget().descend()?;
+    exec(&f.code, env)
+}
+
+/// Creates the frame of a `let*` or `loop`, evaluating each initializer
+/// with the bindings before it in scope.
+fn bind_frame(env: &Environment, names: &Rc<[Symbol]>, inits: &[Expr]) -> EvalResult<Environment> {
+    let child_env = EnvironmentBuilder::new()
+        .with_parent(env)
+        .with_frame(names.clone(), Vec::new())
+        .build();
+    for (slot, init) in inits.iter().enumerate() {
+        let evaluated = exec(init, &child_env)?;
+        child_env.set_local(slot, evaluated);
+    }
+    Ok(child_env)
+}
+
+enum LoopStep {
+    Done(MalVal),
+    Recur(Vec<MalVal>),
+}
+
+/// Evaluates a form in tail position of a loop body. `recur` is only
+/// valid here, which check_recur guarantees before the loop starts.
+fn exec_loop_body(expr: &Expr, env: &Environment) -> EvalResult<LoopStep> {
+    match expr {
+        Expr::Recur(args) => Ok(LoopStep::Recur(
+            args.iter()
+                .map(|v| exec(v, env))
+                .collect::<EvalResult<_>>()?,
+        )),
+        Expr::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            if exec(cond, env)?.is_truthy() {
+                exec_loop_body(then, env)
+            } else if let Some(otherwise) = otherwise {
+                exec_loop_body(otherwise, env)
+            } else {
+                Ok(LoopStep::Done(MalVal::Atom(MalAtom::Nil)))
+            }
+        }
+        Expr::Do(forms) => match forms.split_last() {
+            Some((last, init)) => {
+                for v in init {
+                    exec(v, env)?;
+                }
+                exec_loop_body(last, env)
+            }
+            None => Ok(LoopStep::Done(MalVal::Atom(MalAtom::Nil))),
+        },
+        Expr::Let { names, inits, body } => exec_loop_body(body, &bind_frame(env, names, inits)?),
+        _ => Ok(LoopStep::Done(exec(expr, env)?)),
+    }
+}
+
+/// Verifies that every `recur` in `ast` is in tail position of its
+/// enclosing loop and passes one value per loop binding. `arity` is `None`
+/// when there is no loop to recur to, e.g. inside a `fn*` body.
+fn check_recur(ast: &MalVal, arity: Option<usize>, tail: bool) -> EvalResult<()> {
+    let list = match ast {
+        MalVal::List(list, _) => list,
+        MalVal::Vector(seq, _) => return seq.iter().try_for_each(|v| check_recur(v, arity, false)),
+        MalVal::Set(set, _) => return set.iter().try_for_each(|v| check_recur(v, arity, false)),
+        MalVal::AssocArray(map, _) => {
+            return map.iter().try_for_each(|(k, v)| {
+                check_recur(k, arity, false)?;
+                check_recur(v, arity, false)
+            })
+        }
+        _ => return Ok(()),
+    };
+    let head = match list.front() {
+        Some(MalVal::Atom(MalAtom::Sym(head))) => *head,
+        _ => return list.iter().try_for_each(|v| check_recur(v, arity, false)),
+    };
+    let args: Vec<&MalVal> = list.iter().skip(1).collect();
+
+    match head {
+        Symbol::RECUR => {
+            let expected = arity.ok_or(EvalError::RecurOutsideLoop)?;
+            if !tail {
+                return Err(EvalError::RecurNotInTailPosition);
+            }
+            if args.len() != expected {
+                return Err(EvalError::RecurArityMismatch(expected, args.len()));
+            }
+            args.iter().try_for_each(|v| check_recur(v, arity, false))
+        }
+        Symbol::IF => {
+            if let Some((cond, branches)) = args.split_first() {
+                check_recur(cond, arity, false)?;
+                branches
+                    .iter()
+                    .try_for_each(|v| check_recur(v, arity, tail))?;
+            }
+            Ok(())
+        }
+        Symbol::DO => {
+            if let Some((last, init)) = args.split_last() {
+                init.iter().try_for_each(|v| check_recur(v, arity, false))?;
+                check_recur(last, arity, tail)?;
+            }
+            Ok(())
+        }
+        Symbol::LET | Symbol::LOOP => {
+            let body_arity = if head == Symbol::LOOP {
+                match args.first() {
+                    Some(MalVal::List(vars, _)) | Some(MalVal::Vector(vars, _)) => {
+                        Some(vars.len() / 2)
+                    }
+                    _ => None,
+                }
+            } else {
+                arity
+            };
+            if let Some(MalVal::List(vars, _)) | Some(MalVal::Vector(vars, _)) = args.first() {
+                for v in vars.iter().skip(1).step_by(2) {
+                    check_recur(v, arity, false)?;
+                }
+            }
+            args.iter()
+                .skip(1)
+                .try_for_each(|v| check_recur(v, body_arity, tail || head == Symbol::LOOP))
+        }
+        Symbol::FN => args
+            .iter()
+            .skip(1)
+            .try_for_each(|v| check_recur(v, None, true)),
+        _ => args.iter().try_for_each(|v| check_recur(v, arity, false)),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::{env::InterruptFlag, Seq, SpecialForm, StackTrace};
+    use std::collections::HashMap;
 
-    fn default_env() -> Environment {
-        EnvironmentBuilder::new()
-            .with_builtins(builtin::defaults())
-            .build()
+    fn read(input: &str) -> MalVal {
+        crate::reader::read_str(input).unwrap().remove(0)
     }
 
-    #[test]
-    fn test_eval() {
+    /// Runs the evaluator tests named on both backends, so that they are held
+    /// to the same behavior. Each test takes the backend to run on.
+    macro_rules! backend_tests {
+        ($($name:ident),* $(,)?) => {
+            mod tree_walk {
+                $(
+                    #[test]
+                    fn $name() {
+                        super::$name(super::Backend::TreeWalk);
+                    }
+                )*
+            }
+
+            mod bytecode {
+                $(
+                    #[test]
+                    fn $name() {
+                        super::$name(super::Backend::Bytecode);
+                    }
+                )*
+            }
+        };
+    }
+
+    backend_tests!(
+        test_eval,
+        test_def,
+        test_let,
+        test_fn,
+        test_do,
+        test_if,
+        test_loop,
+        test_max_depth,
+        test_step_limit,
+        test_time_limit,
+        test_interrupt,
+        test_interrupt_from_other_thread,
+        test_trace,
+        test_custom_special_form,
+        test_optimize,
+        test_lexical_scope,
+        test_local_definitions,
+        test_collect_cycles,
+        test_named_fn,
+        test_collections,
+        test_persistent_collections,
+        test_sets,
+        test_chars,
+        test_strings,
+        test_regex,
+        test_meta,
+    );
+
+    fn builder(backend: Backend) -> EnvironmentBuilder {
+        EnvironmentBuilder::new().with_backend(backend)
+    }
+
+    fn default_env(backend: Backend) -> Environment {
+        builder(backend).with_builtins(builtin::defaults()).build()
+    }
+
+    fn test_eval(backend: Backend) {
         {
-            let env = default_env();
+            let env = default_env(backend);
             let ast = MalVal::Atom(MalAtom::Sym("undefined_sym".into()));
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::SymbolNotFound("undefined_sym".into()));
         }
         {
             for op in &["+", "-", "*"] {
-                let env = default_env();
-                let ast = MalVal::Atom(MalAtom::Sym((*op).to_owned()));
+                let env = default_env(backend);
+                let ast = MalVal::Atom(MalAtom::Sym((*op).into()));
                 let expected = ast.clone();
-                let evaluated = eval(ast, &env).unwrap();
+                let evaluated = eval(&ast, &env).unwrap();
 
                 assert_eq!(evaluated, expected);
             }
@@ -234,9 +512,9 @@ mod tests {
             ]
             .into_iter()
             {
-                let env = default_env();
-                let ast = MalVal::List(vec![atom, MalVal::Atom(MalAtom::Int(2))]);
-                let evaluated = eval(ast, &env).unwrap_err();
+                let env = default_env(backend);
+                let ast = MalVal::list(vec![atom, MalVal::Atom(MalAtom::Int(2))]);
+                let evaluated = eval(&ast, &env).unwrap_err();
                 assert!(matches!(evaluated, EvalError::BadFunctionDesignator(_)))
             }
         }
@@ -250,470 +528,1184 @@ mod tests {
                 ]
                 .into_iter()
                 {
-                    let env = default_env();
-                    let ast = MalVal::List(vec![
-                        MalVal::Atom(MalAtom::Sym((*op).to_owned())),
+                    let env = default_env(backend);
+                    let ast = MalVal::list(vec![
+                        MalVal::Atom(MalAtom::Sym((*op).into())),
                         atom,
                         MalVal::Atom(MalAtom::Int(2)),
                     ]);
-                    let evaluated = eval(ast, &env).unwrap_err();
+                    let evaluated = eval(&ast, &env).unwrap_err();
                     assert!(matches!(evaluated, EvalError::NotANumber))
                 }
             }
         }
         {
             for tc in &[("+", 2i64), ("-", -2i64), ("*", 2i64)] {
-                let env = default_env();
+                let env = default_env(backend);
                 let (op, expected) = *tc;
-                let ast = MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym(op.to_owned())),
+                let ast = MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym(op.into())),
                     MalVal::Atom(MalAtom::Int(2)),
                 ]);
-                let evaluated = eval(ast, &env).unwrap();
+                let evaluated = eval(&ast, &env).unwrap();
 
                 assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(expected)));
             }
         }
         {
             for tc in &[("+", 9i64), ("-", -5i64), ("*", 24i64)] {
-                let env = default_env();
+                let env = default_env(backend);
                 let (op, expected) = *tc;
-                let ast = MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym(op.to_owned())),
+                let ast = MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym(op.into())),
                     MalVal::Atom(MalAtom::Int(2)),
                     MalVal::Atom(MalAtom::Int(3)),
                     MalVal::Atom(MalAtom::Int(4)),
                 ]);
-                let evaluated = eval(ast, &env).unwrap();
+                let evaluated = eval(&ast, &env).unwrap();
 
                 assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(expected)));
             }
         }
     }
 
-    #[test]
-    fn test_def() {
+    fn test_def(backend: Backend) {
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![MalVal::Atom(MalAtom::Sym("def!".to_string()))]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![MalVal::Atom(MalAtom::Sym("def!".into()))]);
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::InvalidArgs);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("def!".to_string())),
-                MalVal::Atom(MalAtom::Sym("a".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("def!".into())),
+                MalVal::Atom(MalAtom::Sym("a".into())),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::InvalidArgs);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("def!".to_string())),
-                MalVal::Atom(MalAtom::Sym("a".to_string())),
-                MalVal::Atom(MalAtom::Sym("b".to_string())),
-                MalVal::Atom(MalAtom::Sym("c".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("def!".into())),
+                MalVal::Atom(MalAtom::Sym("a".into())),
+                MalVal::Atom(MalAtom::Sym("b".into())),
+                MalVal::Atom(MalAtom::Sym("c".into())),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::InvalidArgs);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("def!".to_string())),
-                MalVal::Atom(MalAtom::Sym("a".to_string())),
-                MalVal::Atom(MalAtom::Sym("b".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("def!".into())),
+                MalVal::Atom(MalAtom::Sym("a".into())),
+                MalVal::Atom(MalAtom::Sym("b".into())),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert!(matches!(evaluated, EvalError::SymbolNotFound(_)));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("def!".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("def!".into())),
                 MalVal::Atom(MalAtom::Int(1)),
                 MalVal::Atom(MalAtom::Int(2)),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::NotASymbol);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("def!".to_string())),
-                MalVal::Atom(MalAtom::Sym("a".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("def!".into())),
+                MalVal::Atom(MalAtom::Sym("a".into())),
                 MalVal::Atom(MalAtom::Int(2)),
             ]);
-            eval(ast, &env).unwrap();
+            eval(&ast, &env).unwrap();
 
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("+".to_string())),
-                MalVal::Atom(MalAtom::Sym("a".to_string())),
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("+".into())),
+                MalVal::Atom(MalAtom::Sym("a".into())),
                 MalVal::Atom(MalAtom::Int(10)),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
 
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(12)));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("def!".to_string())),
-                MalVal::Atom(MalAtom::Sym("a".to_string())),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("+".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("def!".into())),
+                MalVal::Atom(MalAtom::Sym("a".into())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("+".into())),
                     MalVal::Atom(MalAtom::Int(2)),
                     MalVal::Atom(MalAtom::Int(3)),
                 ]),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
 
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(5)));
         }
     }
 
-    #[test]
-    fn test_let() {
+    fn test_let(backend: Backend) {
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![MalVal::Atom(MalAtom::Sym("let*".to_string()))]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![MalVal::Atom(MalAtom::Sym("let*".into()))]);
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::InvalidArgs);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("let*".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("let*".into())),
                 MalVal::Atom(MalAtom::Int(1)),
                 MalVal::Atom(MalAtom::Int(1)),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::NotAList);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("let*".to_string())),
-                MalVal::List(vec![MalVal::Atom(MalAtom::Int(1))]),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("let*".into())),
+                MalVal::list(vec![MalVal::Atom(MalAtom::Int(1))]),
                 MalVal::Atom(MalAtom::Int(1)),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::InvalidArgs);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("let*".to_string())),
-                MalVal::List(vec![
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("let*".into())),
+                MalVal::list(vec![
                     MalVal::Atom(MalAtom::Int(1)),
                     MalVal::Atom(MalAtom::Int(1)),
                 ]),
                 MalVal::Atom(MalAtom::Int(1)),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::NotASymbol);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("let*".to_string())),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("a".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("let*".into())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("a".into())),
                     MalVal::Atom(MalAtom::Int(7)),
                 ]),
-                MalVal::Atom(MalAtom::Sym("a".to_string())),
+                MalVal::Atom(MalAtom::Sym("a".into())),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
 
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(7)));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("let*".to_string())),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("a".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("let*".into())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("a".into())),
                     MalVal::Atom(MalAtom::Int(7)),
-                    MalVal::Atom(MalAtom::Sym("b".to_string())),
+                    MalVal::Atom(MalAtom::Sym("b".into())),
                     MalVal::Atom(MalAtom::Int(13)),
                 ]),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("+".to_string())),
-                    MalVal::Atom(MalAtom::Sym("a".to_string())),
-                    MalVal::Atom(MalAtom::Sym("b".to_string())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("+".into())),
+                    MalVal::Atom(MalAtom::Sym("a".into())),
+                    MalVal::Atom(MalAtom::Sym("b".into())),
                 ]),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
 
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(20)));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("let*".to_string())),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("a".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("let*".into())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("a".into())),
                     MalVal::Atom(MalAtom::Int(7)),
-                    MalVal::Atom(MalAtom::Sym("b".to_string())),
-                    MalVal::List(vec![
-                        MalVal::Atom(MalAtom::Sym("+".to_string())),
-                        MalVal::Atom(MalAtom::Sym("a".to_string())),
+                    MalVal::Atom(MalAtom::Sym("b".into())),
+                    MalVal::list(vec![
+                        MalVal::Atom(MalAtom::Sym("+".into())),
+                        MalVal::Atom(MalAtom::Sym("a".into())),
                         MalVal::Atom(MalAtom::Int(1)),
                     ]),
                 ]),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("+".to_string())),
-                    MalVal::Atom(MalAtom::Sym("a".to_string())),
-                    MalVal::Atom(MalAtom::Sym("b".to_string())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("+".into())),
+                    MalVal::Atom(MalAtom::Sym("a".into())),
+                    MalVal::Atom(MalAtom::Sym("b".into())),
                 ]),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
 
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(15)));
         }
     }
 
-    #[test]
-    fn test_fn() {
+    fn test_fn(backend: Backend) {
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![MalVal::Atom(MalAtom::Sym("fn*".to_string()))]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![MalVal::Atom(MalAtom::Sym("fn*".into()))]);
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::InvalidArgs);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("fn*".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("fn*".into())),
                 MalVal::Atom(MalAtom::False),
                 MalVal::Atom(MalAtom::False),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::NotAList);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("fn*".to_string())),
-                MalVal::List(vec![MalVal::Atom(MalAtom::False)]),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("fn*".into())),
+                MalVal::list(vec![MalVal::Atom(MalAtom::False)]),
                 MalVal::Atom(MalAtom::False),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
 
             assert_eq!(evaluated, EvalError::NotASymbol);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("fn*".to_string())),
-                MalVal::List(vec![MalVal::Atom(MalAtom::Sym("a".to_string()))]),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("fn*".into())),
+                MalVal::list(vec![MalVal::Atom(MalAtom::Sym("a".into()))]),
                 MalVal::Atom(MalAtom::False),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
 
             assert!(matches!(evaluated, MalVal::Fn(_)));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("fn*".to_string())),
-                MalVal::List(vec![MalVal::Atom(MalAtom::Sym("a".to_string()))]),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("fn*".into())),
+                MalVal::list(vec![MalVal::Atom(MalAtom::Sym("a".into()))]),
                 MalVal::Atom(MalAtom::False),
             ])]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
             assert_eq!(evaluated, EvalError::InvalidArgs);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("fn*".to_string())),
-                MalVal::List(vec![]),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("fn*".into())),
+                MalVal::list(vec![]),
                 MalVal::Atom(MalAtom::False),
             ])]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::False));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("fn*".to_string())),
-                    MalVal::List(vec![MalVal::Atom(MalAtom::Sym("a".to_string()))]),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("fn*".into())),
+                    MalVal::list(vec![MalVal::Atom(MalAtom::Sym("a".into()))]),
                     MalVal::Atom(MalAtom::False),
                 ]),
                 MalVal::Atom(MalAtom::True),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::False));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("fn*".to_string())),
-                    MalVal::List(vec![
-                        MalVal::Atom(MalAtom::Sym("a".to_string())),
-                        MalVal::Atom(MalAtom::Sym("b".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("fn*".into())),
+                    MalVal::list(vec![
+                        MalVal::Atom(MalAtom::Sym("a".into())),
+                        MalVal::Atom(MalAtom::Sym("b".into())),
                     ]),
-                    MalVal::List(vec![
-                        MalVal::Atom(MalAtom::Sym("+".to_string())),
-                        MalVal::Atom(MalAtom::Sym("a".to_string())),
-                        MalVal::Atom(MalAtom::Sym("b".to_string())),
+                    MalVal::list(vec![
+                        MalVal::Atom(MalAtom::Sym("+".into())),
+                        MalVal::Atom(MalAtom::Sym("a".into())),
+                        MalVal::Atom(MalAtom::Sym("b".into())),
                     ]),
                 ]),
                 MalVal::Atom(MalAtom::Int(3)),
                 MalVal::Atom(MalAtom::Int(4)),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(7)));
         }
     }
 
-    #[test]
-    fn test_do() {
+    fn test_do(backend: Backend) {
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![MalVal::Atom(MalAtom::Sym("do".to_string()))]);
-            let evaluated = eval(ast, &env).unwrap();
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![MalVal::Atom(MalAtom::Sym("do".into()))]);
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Nil));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("do".to_string())),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("def!".to_string())),
-                    MalVal::Atom(MalAtom::Sym("a".to_string())),
-                    MalVal::List(vec![
-                        MalVal::Atom(MalAtom::Sym("+".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("do".into())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("def!".into())),
+                    MalVal::Atom(MalAtom::Sym("a".into())),
+                    MalVal::list(vec![
+                        MalVal::Atom(MalAtom::Sym("+".into())),
                         MalVal::Atom(MalAtom::Int(2)),
                         MalVal::Atom(MalAtom::Int(3)),
                     ]),
                 ]),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("+".to_string())),
-                    MalVal::Atom(MalAtom::Sym("a".to_string())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("+".into())),
+                    MalVal::Atom(MalAtom::Sym("a".into())),
                     MalVal::Atom(MalAtom::Int(4)),
                 ]),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(9)));
         }
     }
 
-    #[test]
-    fn test_if() {
+    fn test_if(backend: Backend) {
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![MalVal::Atom(MalAtom::Sym("if".to_string()))]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![MalVal::Atom(MalAtom::Sym("if".into()))]);
+            let evaluated = eval(&ast, &env).unwrap_err();
             assert_eq!(evaluated, EvalError::InvalidArgs);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("if".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("if".into())),
                 MalVal::Atom(MalAtom::False),
             ]);
-            let evaluated = eval(ast, &env).unwrap_err();
+            let evaluated = eval(&ast, &env).unwrap_err();
             assert_eq!(evaluated, EvalError::InvalidArgs);
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("if".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("if".into())),
                 MalVal::Atom(MalAtom::False),
                 MalVal::Atom(MalAtom::Int(7)),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Nil));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("if".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("if".into())),
                 MalVal::Atom(MalAtom::False),
                 MalVal::Atom(MalAtom::Int(7)),
                 MalVal::Atom(MalAtom::Int(9)),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(9)));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("if".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("if".into())),
                 MalVal::Atom(MalAtom::True),
                 MalVal::Atom(MalAtom::Int(7)),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(7)));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("if".to_string())),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("=".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("if".into())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("=".into())),
                     MalVal::Atom(MalAtom::Int(1)),
                     MalVal::Atom(MalAtom::Int(2)),
                 ]),
                 MalVal::Atom(MalAtom::Int(7)),
                 MalVal::Atom(MalAtom::Int(9)),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(9)));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("if".to_string())),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("=".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("if".into())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("=".into())),
                     MalVal::Atom(MalAtom::Int(1)),
                     MalVal::Atom(MalAtom::Int(1)),
                 ]),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("+".to_string())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("+".into())),
                     MalVal::Atom(MalAtom::Int(1)),
                     MalVal::Atom(MalAtom::Int(2)),
                 ]),
                 MalVal::Atom(MalAtom::Int(9)),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(3)));
         }
         {
-            let env = default_env();
-            let ast = MalVal::List(vec![
-                MalVal::Atom(MalAtom::Sym("if".to_string())),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("=".to_string())),
+            let env = default_env(backend);
+            let ast = MalVal::list(vec![
+                MalVal::Atom(MalAtom::Sym("if".into())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("=".into())),
                     MalVal::Atom(MalAtom::Int(1)),
                     MalVal::Atom(MalAtom::Int(2)),
                 ]),
                 MalVal::Atom(MalAtom::Int(9)),
-                MalVal::List(vec![
-                    MalVal::Atom(MalAtom::Sym("+".to_string())),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("+".into())),
                     MalVal::Atom(MalAtom::Int(1)),
                     MalVal::Atom(MalAtom::Int(2)),
                 ]),
             ]);
-            let evaluated = eval(ast, &env).unwrap();
+            let evaluated = eval(&ast, &env).unwrap();
             assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(3)));
         }
     }
+
+    fn test_loop(backend: Backend) {
+        {
+            let env = default_env(backend);
+            let ast = read("(loop [i 0 acc 1] (if (< i 10) (recur (+ i 1) (* acc 2)) acc))");
+            let evaluated = eval(&ast, &env).unwrap();
+            assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(1024)));
+        }
+        {
+            let env = default_env(backend);
+            let ast =
+                read("(loop (i 0) (do (def! last i) (if (= i 3) (let* (j i) j) (recur (+ i 1)))))");
+            let evaluated = eval(&ast, &env).unwrap();
+            assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(3)));
+        }
+        {
+            // Deep enough that plain recursion through apply_fn would overflow.
+            let env = default_env(backend);
+            let ast = read("(loop [i 0] (if (< i 20000) (recur (+ i 1)) i))");
+            let evaluated = eval(&ast, &env).unwrap();
+            assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(20000)));
+        }
+        {
+            let env = default_env(backend);
+            let ast = read("(loop [i 0] (loop [j 5] (if (< i j) (recur (- j 1)) j)))");
+            let evaluated = eval(&ast, &env).unwrap();
+            assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(0)));
+        }
+        {
+            let env = default_env(backend);
+            let ast = read("(loop [i 0] (+ 1 (recur i)))");
+            let evaluated = eval(&ast, &env).unwrap_err();
+            assert_eq!(evaluated, EvalError::RecurNotInTailPosition);
+        }
+        {
+            let env = default_env(backend);
+            let ast = read("(loop [i 0] (if (recur 1) 1 2))");
+            let evaluated = eval(&ast, &env).unwrap_err();
+            assert_eq!(evaluated, EvalError::RecurNotInTailPosition);
+        }
+        {
+            let env = default_env(backend);
+            let ast = read("(loop [i 0 j 0] (recur 1))");
+            let evaluated = eval(&ast, &env).unwrap_err();
+            assert_eq!(evaluated, EvalError::RecurArityMismatch(2, 1));
+        }
+        {
+            let env = default_env(backend);
+            let ast = read("(loop [i 0] (fn* () (recur 1)))");
+            let evaluated = eval(&ast, &env).unwrap_err();
+            assert_eq!(evaluated, EvalError::RecurOutsideLoop);
+        }
+        {
+            let env = default_env(backend);
+            let ast = read("(recur 1)");
+            let evaluated = eval(&ast, &env).unwrap_err();
+            assert_eq!(evaluated, EvalError::RecurOutsideLoop);
+        }
+        {
+            // Caught when the function is analyzed, not when the branch runs.
+            let env = default_env(backend);
+            let ast = read("((fn* (a) (if (= a 0) 0 (recur))) 0)");
+            let evaluated = eval(&ast, &env).unwrap_err();
+            assert_eq!(evaluated, EvalError::RecurOutsideLoop);
+        }
+        {
+            let env = default_env(backend);
+            let ast = read("(loop [i] i)");
+            let evaluated = eval(&ast, &env).unwrap_err();
+            assert_eq!(evaluated, EvalError::InvalidArgs);
+        }
+        {
+            let env = default_env(backend);
+            let ast = read("(loop 1 1)");
+            let evaluated = eval(&ast, &env).unwrap_err();
+            assert_eq!(evaluated, EvalError::NotAList);
+        }
+    }
+
+    fn test_max_depth(backend: Backend) {
+        let env = builder(backend)
+            .with_builtins(builtin::defaults())
+            .with_max_depth(Some(100))
+            .build();
+        eval(&read("(def! f (fn* (n) (+ 1 (f (+ n 1)))))"), &env).unwrap();
+        let evaluated = eval(&read("(f 0)"), &env).unwrap_err();
+        if let EvalError::Traced(err, _) = evaluated {
+            assert_eq!(*err, EvalError::StackOverflow(101));
+        } else {
+            panic!("expected a traced error, got {:?}", evaluated);
+        }
+
+        // The depth is released again after the error unwinds.
+        eval(
+            &read("(def! g (fn* (n) (if (= n 0) 0 (+ 1 (g (- n 1))))))"),
+            &env,
+        )
+        .unwrap();
+        let evaluated = eval(&read("(g 10)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(10)));
+    }
+
+    fn test_step_limit(backend: Backend) {
+        let env = builder(backend)
+            .with_builtins(builtin::defaults())
+            .with_step_limit(Some(40))
+            .build();
+        let evaluated = eval(&read("(loop [i 0] (recur (+ i 1)))"), &env).unwrap_err();
+        assert_eq!(evaluated, EvalError::StepLimitExceeded(40));
+
+        // Each (+ 1 2) takes four or five steps depending on the
+        // backend, so the second run of the do form below exceeds the
+        // budget while the first one fits.
+        env.reset_budget();
+        let evaluated = eval(&read("(+ 1 (* 2 3))"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(7)));
+        env.reset_budget();
+        let evaluated = eval(
+            &read("(do (+ 1 2) (+ 1 2) (+ 1 2) (+ 1 2) (+ 1 2) (+ 1 2))"),
+            &env,
+        );
+        assert_eq!(evaluated, Ok(MalVal::Atom(MalAtom::Int(3))));
+        let evaluated = eval(
+            &read("(do (+ 1 2) (+ 1 2) (+ 1 2) (+ 1 2) (+ 1 2) (+ 1 2))"),
+            &env,
+        );
+        assert_eq!(evaluated, Err(EvalError::StepLimitExceeded(40)));
+    }
+
+    fn test_time_limit(backend: Backend) {
+        let env = builder(backend)
+            .with_builtins(builtin::defaults())
+            .with_time_limit(Some(std::time::Duration::from_millis(0)))
+            .build();
+        let evaluated = eval(&read("(+ 1 2)"), &env).unwrap_err();
+        assert_eq!(evaluated, EvalError::DeadlineExceeded);
+    }
+
+    fn test_interrupt(backend: Backend) {
+        let interrupt = InterruptFlag::new();
+        let env = builder(backend)
+            .with_builtins(builtin::defaults())
+            .with_interrupt(interrupt.clone())
+            .build();
+
+        interrupt.interrupt();
+        let evaluated = eval(&read("(loop [i 0] (recur (+ i 1)))"), &env).unwrap_err();
+        assert_eq!(evaluated, EvalError::Interrupted);
+
+        // Reporting the interruption clears it.
+        let evaluated = eval(&read("(+ 1 2)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(3)));
+
+        interrupt.interrupt();
+        env.reset_budget();
+        let evaluated = eval(&read("(+ 1 2)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(3)));
+    }
+
+    fn test_interrupt_from_other_thread(backend: Backend) {
+        let interrupt = InterruptFlag::new();
+        let env = builder(backend)
+            .with_builtins(builtin::defaults())
+            .with_interrupt(interrupt.clone())
+            .build();
+
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            interrupt.interrupt();
+        });
+        let evaluated = eval(&read("(loop [i 0] (recur (+ i 1)))"), &env).unwrap_err();
+        handle.join().unwrap();
+        assert_eq!(evaluated, EvalError::Interrupted);
+    }
+
+    fn test_trace(backend: Backend) {
+        let env = default_env(backend);
+        eval(&read("(def! add (fn* (a b) (+ a b)))"), &env).unwrap();
+        eval(&read("(def! outer (fn* (x) (add 1 x)))"), &env).unwrap();
+        let evaluated = eval(&read("(outer nil)"), &env).unwrap_err();
+
+        assert_eq!(
+            evaluated,
+            EvalError::Traced(
+                Box::new(EvalError::NotANumber),
+                StackTrace {
+                    frames: vec![
+                        Frame::new(Some("add".to_owned()), read("(add 1 x)")),
+                        Frame::new(Some("outer".to_owned()), read("(outer nil)")),
+                    ],
+                    omitted: 0,
+                }
+            )
+        );
+        assert_eq!(
+            evaluated.to_string(),
+            "Not a number\n  at add: (add 1 x)\n  at outer: (outer nil)"
+        );
+
+        // Errors outside of any function call are not traced.
+        let evaluated = eval(&read("(+ 1 nil)"), &env).unwrap_err();
+        assert_eq!(evaluated, EvalError::NotANumber);
+
+        let evaluated = eval(&read("((fn* (a) (+ a nil)) 1)"), &env).unwrap_err();
+        assert_eq!(
+            evaluated.to_string(),
+            "Not a number\n  at <anonymous>: ((fn* (a) (+ a nil)) 1)"
+        );
+    }
+
+    fn test_custom_special_form(backend: Backend) {
+        fn quote(_env: &Environment, list: &Seq) -> EvalResult<MalVal> {
+            if list.len() != 2 {
+                return Err(EvalError::InvalidArgs);
+            }
+            Ok(list[1].clone())
+        }
+
+        let mut forms: HashMap<String, SpecialForm> = HashMap::new();
+        forms.insert("quote".to_owned(), quote);
+        let env = builder(backend)
+            .with_builtins(builtin::defaults())
+            .with_special_forms(forms)
+            .build();
+
+        let evaluated = eval(&read("(quote (a b))"), &env).unwrap();
+        assert_eq!(evaluated, read("(a b)"));
+        let evaluated = eval(&read("(let* (x 1) (quote x))"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Sym("x".into())));
+        let evaluated = eval(&read("(quote)"), &env).unwrap_err();
+        assert_eq!(evaluated, EvalError::InvalidArgs);
+
+        // Standard forms are still available.
+        let evaluated = eval(&read("(if (quote x) 1 2)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(1)));
+
+        // Without registration, quote is an ordinary symbol.
+        let evaluated = eval(&read("(quote x)"), &default_env(backend)).unwrap_err();
+        assert_eq!(evaluated, EvalError::SymbolNotFound("quote".to_owned()));
+
+        // Registered forms replace standard ones of the same name.
+        let mut forms: HashMap<String, SpecialForm> = HashMap::new();
+        forms.insert("if".to_owned(), quote);
+        let env = builder(backend).with_special_forms(forms).build();
+        let evaluated = eval(&read("(if (undefined))"), &env).unwrap();
+        assert_eq!(evaluated, read("(undefined)"));
+    }
+
+    fn test_optimize(backend: Backend) {
+        let env = builder(backend)
+            .with_builtins(builtin::defaults())
+            .with_pure_builtins(builtin::pure())
+            .with_optimize(true)
+            .build();
+        let evaluated = eval(&read("(if (< 1 2) (+ 1 2) (undefined))"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(3)));
+
+        eval(
+            &read("(def! f (fn* (a) (if false (undefined) (* a (+ 1 1)))))"),
+            &env,
+        )
+        .unwrap();
+        let evaluated = eval(&read("(f 4)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(8)));
+
+        // Errors from builtins are still raised at run time.
+        let evaluated = eval(&read("(if true (+ 1 nil))"), &env).unwrap_err();
+        assert_eq!(evaluated, EvalError::NotANumber);
+    }
+
+    fn test_lexical_scope(backend: Backend) {
+        let env = default_env(backend);
+        let evaluated = eval(
+            &read("(((fn* (a) (fn* (b) (let* (c 3) (+ a (+ b c))))) 1) 2)"),
+            &env,
+        )
+        .unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(6)));
+
+        // def! inside a function defines a local of the call frame.
+        eval(&read("(def! f (fn* (a) (do (def! b (+ a 1)) b)))"), &env).unwrap();
+        let evaluated = eval(&read("(f 1)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(2)));
+        let evaluated = eval(&read("b"), &env).unwrap_err();
+        assert_eq!(evaluated, EvalError::SymbolNotFound("b".to_owned()));
+
+        // Locals shadow globals and builtins.
+        eval(&read("(def! a 10)"), &env).unwrap();
+        let evaluated = eval(&read("(let* (a 1 + 2) (list a +))"), &env).unwrap();
+        assert_eq!(evaluated, read("(1 2)"));
+
+        // Forms evaluated by embedder special forms still see locals.
+        fn twice(env: &Environment, list: &Seq) -> EvalResult<MalVal> {
+            eval(&list[1], env)?;
+            eval(&list[1], env)
+        }
+        let mut forms: HashMap<String, SpecialForm> = HashMap::new();
+        forms.insert("twice".to_owned(), twice);
+        let env = builder(backend)
+            .with_builtins(builtin::defaults())
+            .with_special_forms(forms)
+            .build();
+        let evaluated = eval(&read("((fn* (x) (let* (y 2) (twice (+ x y)))) 1)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(3)));
+    }
+
+    fn test_local_definitions(backend: Backend) {
+        let env = default_env(backend);
+
+        // A binding is in scope in its own initializer.
+        let evaluated = eval(
+            &read("(let* (f (fn* (n) (if (= n 0) :done (f (- n 1))))) (f 3))"),
+            &env,
+        )
+        .unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Keyword("done".to_owned())));
+        // Until it is assigned, earlier bindings of the name are seen.
+        let evaluated = eval(&read("(let* (a 1 a (+ a 1)) a)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(2)));
+
+        // Functions can refer to locals defined after them.
+        let evaluated = eval(
+            &read("(let* (x 1) (do (def! f (fn* () (g))) (def! g (fn* () x)) (f)))"),
+            &env,
+        )
+        .unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(1)));
+
+        // A local definition that has not run leaves the global visible.
+        eval(&read("(def! b 10)"), &env).unwrap();
+        let evaluated = eval(&read("((fn* (a) (do (if a (def! b 1)) b)) false)"), &env);
+        assert_eq!(evaluated, Ok(MalVal::Atom(MalAtom::Int(10))));
+        let evaluated = eval(&read("((fn* (a) (do (if a (def! b 1)) b)) true)"), &env);
+        assert_eq!(evaluated, Ok(MalVal::Atom(MalAtom::Int(1))));
+        let evaluated = eval(&read("(let* () (do (if false (def! c 1)) c))"), &env);
+        assert_eq!(evaluated, Err(EvalError::SymbolNotFound("c".to_owned())));
+    }
+
+    fn test_collect_cycles(backend: Backend) {
+        let env = builder(backend).with_builtins(builtin::defaults()).build();
+
+        // A function defined in the frame it closes over.
+        let f = eval(&read("(let* (x 1) (do (def! g (fn* () x)) g))"), &env).unwrap();
+        assert_eq!(env.collect_cycles(), 0);
+        let call_env = builder(backend).with_parent(&env).build();
+        call_env.set("f".into(), f.clone());
+        let evaluated = eval(&read("(f)"), &call_env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(1)));
+        drop(call_env);
+
+        // Closures stored in a vector are never collected while reachable.
+        eval(
+            &read("(def! fs (let* (y 2) (do (def! h (fn* () y)) [h])))"),
+            &env,
+        )
+        .unwrap();
+        assert_eq!(env.collect_cycles(), 0);
+
+        drop(f);
+        assert_eq!(env.collect_cycles(), 1);
+        assert_eq!(env.collect_cycles(), 0);
+
+        // Debug output and equality do not follow the cycle.
+        let h = eval(&read("(let* (z 3) (do (def! k (fn* () z)) k))"), &env).unwrap();
+        assert!(format!("{:?}", h).contains("Environment"));
+        assert_eq!(h, h.clone());
+        assert_eq!(env, env.clone());
+        assert_ne!(env, builder(backend).build());
+    }
+
+    fn test_named_fn(backend: Backend) {
+        let env = default_env(backend);
+        let evaluated = eval(&read("(fn* (a b) (+ a b))"), &env).unwrap();
+        assert_eq!(evaluated.to_string(), "#<fn [a b]>");
+
+        let evaluated = eval(
+            &read("(def! add (fn* (a b) \"Adds a and b\" (+ a b)))"),
+            &env,
+        )
+        .unwrap();
+        assert_eq!(evaluated.to_string(), "#<fn add [a b]>");
+        if let MalVal::Fn(f) = &evaluated {
+            assert_eq!(f.name, Some("add".to_owned()));
+            assert_eq!(f.doc, Some("Adds a and b".to_owned()));
+            assert_eq!(f.binds, vec![Symbol::from("a"), Symbol::from("b")]);
+            assert_eq!(
+                f.span.map(|span| span.to_string()).as_deref(),
+                Some("1:11-1:44")
+            );
+        } else {
+            panic!("expected a function, got {}", evaluated);
+        }
+
+        // Rebinding keeps the original name.
+        let evaluated = eval(&read("(def! plus add)"), &env).unwrap();
+        assert_eq!(evaluated.to_string(), "#<fn add [a b]>");
+        let evaluated = eval(&read("(plus 1 2)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(3)));
+
+        let evaluated = eval(&read("(doc add)"), &env).unwrap();
+        assert_eq!(
+            evaluated,
+            MalVal::Atom(MalAtom::Str(
+                "add [a b]\n  Adds a and b\n  defined at 1:11-1:44".to_owned()
+            ))
+        );
+
+        // A single string body is the return value, not a docstring.
+        let evaluated = eval(&read("((fn* () \"hello\"))"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Str("hello".to_owned())));
+
+        let evaluated = eval(&read("(fn* () 1 2)"), &env).unwrap_err();
+        assert_eq!(evaluated, EvalError::InvalidArgs);
+
+        // Traces use the function name rather than the symbol it was called through.
+        let evaluated = eval(&read("(plus 1 nil)"), &env).unwrap_err();
+        assert_eq!(
+            evaluated.to_string(),
+            "Not a number\n  at add: (plus 1 nil)"
+        );
+    }
+
+    fn test_collections(backend: Backend) {
+        let env = default_env(backend);
+        let evaluated = eval(&read("[1 (+ 1 1) :three]"), &env).unwrap();
+        assert_eq!(evaluated.to_string(), "[1 2 :three]");
+
+        let evaluated = eval(&read("{:a (+ 1 1) \"b\" [3]}"), &env).unwrap();
+        assert_eq!(evaluated, read("{\"b\" [3] :a 2}"));
+
+        let evaluated = eval(&read("{(+ 1 1) :two}"), &env).unwrap();
+        assert_eq!(evaluated.to_string(), "{2 :two}");
+
+        let res = eval(&read("(let* (a 1 b 1) {a :x b :y})"), &env);
+        assert_eq!(res, Err(EvalError::DuplicateKey("1".to_owned())));
+    }
+
+    fn test_persistent_collections(backend: Backend) {
+        let env = default_env(backend);
+        eval(&read("(def! v [1 2 3])"), &env).unwrap();
+        eval(&read("(def! m {:a 1})"), &env).unwrap();
+        for (input, expected) in &[
+            ("(conj v 4 5)", "[1 2 3 4 5]"),
+            ("(conj (list 1 2) 3 4)", "(4 3 1 2)"),
+            ("(cons 0 v)", "(0 1 2 3)"),
+            ("(assoc v 0 :x)", "[:x 2 3]"),
+            ("(assoc m :a 2)", "{:a 2}"),
+            ("(get (assoc m :b 2) :b)", "2"),
+            ("(get (dissoc m :a) :a)", "nil"),
+            ("(count (conj m [:b 2]))", "2"),
+            ("(get v 1)", "2"),
+            // The originals are untouched by the updates above.
+            ("v", "[1 2 3]"),
+            ("m", "{:a 1}"),
+            ("(= (hash-map :a 1 :b 2) {:b 2 :a 1})", "true"),
+            (
+                "(count (loop [acc [] i 0] (if (< i 1000) (recur (conj acc i) (+ i 1)) acc)))",
+                "1000",
+            ),
+        ] {
+            let evaluated = eval(&read(input), &env).unwrap();
+            assert_eq!(evaluated.to_string(), *expected, "{}", input);
+        }
+    }
+
+    fn test_sets(backend: Backend) {
+        let env = default_env(backend);
+        eval(&read("(def! s #{1 2 3})"), &env).unwrap();
+        for (input, expected) in &[
+            ("#{(+ 1 1) (- 2 1) 2}", "#{1 2}"),
+            ("(set [3 1 3])", "#{1 3}"),
+            ("(set {:a 1})", "#{[:a 1]}"),
+            ("(set nil)", "#{}"),
+            ("(conj s 4 1)", "#{1 2 3 4}"),
+            ("(disj s 1 5)", "#{2 3}"),
+            ("(union s #{4} #{5})", "#{1 2 3 4 5}"),
+            ("(union)", "#{}"),
+            ("(intersection s #{2 3 4} #{3 2})", "#{2 3}"),
+            ("(difference s #{2} #{3})", "#{1}"),
+            ("s", "#{1 2 3}"),
+        ] {
+            let evaluated = eval(&read(input), &env).unwrap();
+            assert_eq!(evaluated, read(expected), "{}", input);
+        }
+        for (input, expected) in &[
+            ("(set? s)", "true"),
+            ("(set? [1])", "false"),
+            ("(= #{[1] #{}} #{#{} [1]})", "true"),
+            ("(= #{1} [1])", "false"),
+            ("(contains? s 2)", "true"),
+            ("(contains? s 4)", "false"),
+            ("(contains? {:a nil} :a)", "true"),
+            ("(contains? [5 6] 1)", "true"),
+            ("(contains? [5 6] 2)", "false"),
+            ("(count s)", "3"),
+            ("(get s 3)", "3"),
+            ("(get s 4)", "nil"),
+            ("(meta ^{:a 1} #{})", "{:a 1}"),
+        ] {
+            let evaluated = eval(&read(input), &env).unwrap();
+            assert_eq!(evaluated.to_string(), *expected, "{}", input);
+        }
+
+        let evaluated = eval(&read("(union s [1])"), &env).unwrap_err();
+        assert_eq!(
+            evaluated,
+            EvalError::UnexpectedType("a set", "[1]".to_owned())
+        );
+    }
+
+    fn test_chars(backend: Backend) {
+        let env = default_env(backend);
+        for (input, expected) in &[
+            ("(char 97)", "\\a"),
+            ("(char \"b\")", "\\b"),
+            ("(char \\c)", "\\c"),
+            ("(int->char 10)", "\\newline"),
+            ("(char->int \\space)", "32"),
+            ("(char? \\a)", "true"),
+            ("(char? \"a\")", "false"),
+            ("(seq \"héy\")", "(\\h \\é \\y)"),
+            ("(seq \"\")", "nil"),
+            ("(seq [1 2])", "(1 2)"),
+            ("(seq {:a 1})", "([:a 1])"),
+            ("(seq nil)", "nil"),
+            ("(= (seq \"ab\") (list \\a \\b))", "true"),
+        ] {
+            let evaluated = eval(&read(input), &env).unwrap();
+            assert_eq!(evaluated.to_string(), *expected, "{}", input);
+        }
+
+        assert!(matches!(
+            eval(&read("(int->char -1)"), &env),
+            Err(EvalError::Conversion(_))
+        ));
+        assert!(matches!(
+            eval(&read("(char \"ab\")"), &env),
+            Err(EvalError::UnexpectedType(..))
+        ));
+    }
+
+    fn test_strings(backend: Backend) {
+        let env = default_env(backend);
+        for (input, expected) in &[
+            ("(str/split \"a,b,,c\" \",\")", "[\"a\" \"b\" \"\" \"c\"]"),
+            ("(str/split \"añb\" \"\")", "[\"a\" \"ñ\" \"b\"]"),
+            ("(str/split \"a b\" \\space)", "[\"a\" \"b\"]"),
+            ("(str/join [1 \"b\" \\c nil :d])", "\"1bc:d\""),
+            ("(str/join \", \" (list 1 2))", "\"1, 2\""),
+            ("(str/trim \"\u{3000} x y\n\")", "\"x y\""),
+            ("(str/upper-case \"straße\")", "\"STRASSE\""),
+            ("(str/lower-case \"ÀB\")", "\"àb\""),
+            ("(str/replace \"a.b.c\" \".\" \"::\")", "\"a::b::c\""),
+            ("(str/starts-with? \"ñandu\" \"ña\")", "true"),
+            ("(str/starts-with? \"ñandu\" \"n\")", "false"),
+            ("(str/index-of \"ñandu\" \"d\")", "3"),
+            ("(subs \"ñandu\" 1 3)", "\"an\""),
+            ("(str/format \"{} is {1}\" :x [1])", "\":x is [1]\""),
+        ] {
+            let evaluated = eval(&read(input), &env).unwrap();
+            assert_eq!(evaluated.to_string(), *expected, "{}", input);
+        }
+
+        assert!(matches!(
+            eval(&read("(str/trim 1)"), &env),
+            Err(EvalError::UnexpectedType("a string", _))
+        ));
+    }
+
+    fn test_regex(backend: Backend) {
+        let env = default_env(backend);
+        eval(
+            &read(r#"(def! line "2021-03-04 ERROR [db] timeout after 30s")"#),
+            &env,
+        )
+        .unwrap();
+        for (input, expected) in &[
+            (
+                r#"(re-find #"(\d{4})-(\d\d)-(\d\d) (\w+)" line)"#,
+                r#"["2021-03-04 ERROR" "2021" "03" "04" "ERROR"]"#,
+            ),
+            (r#"(re-find #"\[(\w+)\]" line)"#, r#"["[db]" "db"]"#),
+            (r#"(re-seq #"\d+" line)"#, r#"("2021" "03" "04" "30")"#),
+            (r#"(re-seq #"x" line)"#, "nil"),
+            (r#"(re-matches #"\d+" "123")"#, r#""123""#),
+            (r#"(re-matches #"\d+" line)"#, "nil"),
+            (r#"(re-find (re-pattern "E\\w+") line)"#, r#""ERROR""#),
+            (
+                r#"(str/replace line #"(\d+)s" "${1} seconds")"#,
+                r#""2021-03-04 ERROR [db] timeout after 30 seconds""#,
+            ),
+            (r#"(str/replace "a.b" "." "-")"#, r#""a-b""#),
+            (r#"(= #"a+" (re-pattern "a+"))"#, "true"),
+        ] {
+            let evaluated = eval(&read(input), &env).unwrap();
+            assert_eq!(evaluated.to_string(), *expected, "{}", input);
+        }
+
+        assert!(matches!(
+            eval(&read(r#"(re-find "a" "a")"#), &env),
+            Err(EvalError::UnexpectedType("a regex", _))
+        ));
+    }
+
+    fn test_meta(backend: Backend) {
+        let env = default_env(backend);
+        for (input, expected) in &[
+            ("(meta (with-meta [1 2] {:a 1}))", "{:a 1}"),
+            ("(meta ^{:a 1} (list 1 2))", "{:a 1}"),
+            ("(meta ^{:a 1} {:b 2})", "{:a 1}"),
+            ("(meta ^{:a 1} (fn* () 1))", "{:a 1}"),
+            ("(meta [1 2])", "nil"),
+            ("(meta 1)", "nil"),
+            ("^{:a 1} [1 2]", "[1 2]"),
+            ("(= ^{:a 1} [1 2] [1 2])", "true"),
+            ("(= ^{:a 1} [1 2] ^{:a 2} [1 2])", "true"),
+            ("(meta (with-meta ^{:a 1} [1] {:b 2}))", "{:b 2}"),
+            (
+                "(doc ^{:doc \"Identity\"} (fn* (a) a))",
+                "\"[a]\n  Identity\n  defined at 1:25-1:35\"",
+            ),
+        ] {
+            let evaluated = eval(&read(input), &env).unwrap();
+            assert_eq!(evaluated.to_string(), *expected, "{}", input);
+        }
+
+        let evaluated = eval(&read("(with-meta 1 {:a 1})"), &env).unwrap_err();
+        assert_eq!(evaluated, EvalError::InvalidArgs);
+    }
+
+    #[test]
+    fn test_bytecode_tail_calls() {
+        let env = builder(Backend::Bytecode)
+            .with_builtins(builtin::defaults())
+            .with_max_depth(Some(100))
+            .build();
+        eval(
+            &read("(def! f (fn* (n) (if (= n 0) :done (f (- n 1)))))"),
+            &env,
+        )
+        .unwrap();
+        let evaluated = eval(&read("(f 10000)"), &env).unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Keyword("done".into())));
+
+        // Tail calls still show up in stack traces.
+        eval(
+            &read("(def! g (fn* (n) (if (= n 0) (+ n nil) (g (- n 1)))))"),
+            &env,
+        )
+        .unwrap();
+        let evaluated = eval(&read("(g 2)"), &env).unwrap_err();
+        assert_eq!(
+            evaluated.to_string(),
+            "Not a number\n  at g: (g (- n 1))\n  at g: (g (- n 1))\n  at g: (g 2)"
+        );
+    }
+
+    #[test]
+    fn test_depth_agrees_across_backends() {
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let env = EnvironmentBuilder::new()
+                .with_builtins(builtin::defaults())
+                .with_backend(backend)
+                .with_max_depth(Some(100))
+                .build();
+            eval(
+                &read("(def! f (fn* (n) (if (= n 0) 0 (+ 1 (let* (m (- n 1)) (f m))))))"),
+                &env,
+            )
+            .unwrap();
+            // The top-level form and 99 calls, none of them tail calls.
+            // Forms nested within a call do not count.
+            let evaluated = eval(&read("(+ 0 (f 98))"), &env);
+            assert_eq!(
+                evaluated,
+                Ok(MalVal::Atom(MalAtom::Int(98))),
+                "{:?}",
+                backend
+            );
+            match eval(&read("(+ 0 (f 99))"), &env).unwrap_err() {
+                EvalError::Traced(err, _) => {
+                    assert_eq!(*err, EvalError::StackOverflow(101), "{:?}", backend)
+                }
+                err => panic!("expected a traced error, got {:?}", err),
+            }
+        }
+    }
+
+    /// Rough timing of a call-heavy program on each backend. Run with
+    /// `cargo test --release -- --ignored --nocapture bench_fib`. To compare
+    /// whole revisions instead, use `scripts/bench-fib.sh <rev>...`.
+    #[test]
+    #[ignore]
+    fn bench_fib() {
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let env = EnvironmentBuilder::new()
+                .with_builtins(builtin::defaults())
+                .with_backend(backend)
+                .with_max_depth(None)
+                .build();
+            eval(
+                &read("(def! fib (fn* (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))"),
+                &env,
+            )
+            .unwrap();
+
+            let runs = 5;
+            let start = std::time::Instant::now();
+            for _ in 0..runs {
+                let evaluated = eval(&read("(fib 25)"), &env).unwrap();
+                assert_eq!(evaluated, MalVal::Atom(MalAtom::Int(75025)));
+            }
+            println!(
+                "{:?} fib(25): {:?} per run",
+                backend,
+                start.elapsed() / runs
+            );
+        }
+    }
 }
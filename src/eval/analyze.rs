@@ -0,0 +1,456 @@
+use std::{collections::HashMap, rc::Rc};
+
+use itertools::Itertools;
+
+use super::check_recur;
+use crate::types::{
+    env::Environment, symbol::Symbol, EvalError, EvalResult, MalAtom, MalVal, Meta, Seq, Span,
+    SpecialForm,
+};
+
+/// A form after lexical analysis. Variables bound by `fn*`, `let*` and
+/// `loop` are resolved to a frame and slot, so only globals are looked up
+/// by name at run time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A value that evaluates to itself.
+    Const(MalVal),
+    /// A local variable, `depth` frames up from the current one.
+    Local {
+        depth: usize,
+        slot: usize,
+    },
+    /// A variable defined with `def!` outside any local frame, or a builtin.
+    Global(Symbol),
+    Vector(Vec<Expr>, Meta),
+    Map(Vec<(Expr, Expr)>, Meta),
+    Set(Vec<Expr>, Meta),
+    /// `def!`. Definitions inside a local frame store into `slot`.
+    Def {
+        sym: Symbol,
+        slot: Option<usize>,
+        value: Box<Expr>,
+    },
+    Let {
+        names: Rc<[Symbol]>,
+        inits: Vec<Expr>,
+        body: Box<Expr>,
+    },
+    Fn(Rc<Lambda>),
+    Do(Vec<Expr>),
+    If {
+        cond: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Option<Box<Expr>>,
+    },
+    /// `loop`. The first `inits.len()` slots of each iteration's frame hold
+    /// the loop bindings.
+    Loop {
+        names: Rc<[Symbol]>,
+        inits: Vec<Expr>,
+        body: Box<Expr>,
+    },
+    Recur(Vec<Expr>),
+    /// A function application. `form` is kept for stack traces.
+    Call {
+        form: MalVal,
+        head: Option<Symbol>,
+        items: Vec<Expr>,
+    },
+    /// A special form registered by an embedder under the given name. It is
+    /// handed the unevaluated form at run time.
+    Special(Symbol, Seq),
+}
+
+/// An analyzed `fn*`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lambda {
+    pub binds: Vec<Symbol>,
+    /// Names of all slots in a call frame: the parameters followed by any
+    /// locals introduced with `def!` in the body.
+    pub names: Rc<[Symbol]>,
+    pub body: MalVal,
+    pub code: Rc<Expr>,
+    pub doc: Option<String>,
+    pub span: Option<Span>,
+}
+
+/// An entry in the special form registry, see `Environment::special_form`.
+#[derive(Clone, Copy, Debug)]
+pub enum Form {
+    /// A standard form, which the analyzer turns into an `Expr`. It is
+    /// handed the whole form, head included, and the form's metadata.
+    Standard(fn(&mut Analyzer, &Seq, &Meta) -> EvalResult<Expr>),
+    /// A form registered by an embedder, evaluated at run time.
+    Custom(SpecialForm),
+}
+
+/// The special forms every environment starts with.
+pub fn special_forms() -> HashMap<String, Form> {
+    let mut forms: HashMap<String, Form> = HashMap::new();
+    forms.insert(
+        "def!".to_owned(),
+        Form::Standard(|a, list, _| a.analyze_def(list)),
+    );
+    forms.insert(
+        "let*".to_owned(),
+        Form::Standard(|a, list, _| a.analyze_let(list)),
+    );
+    forms.insert(
+        "fn*".to_owned(),
+        Form::Standard(|a, list, meta| a.analyze_fn(list, meta)),
+    );
+    forms.insert(
+        "do".to_owned(),
+        Form::Standard(|a, list, _| Ok(Expr::Do(a.analyze_all(list.iter().skip(1))?))),
+    );
+    forms.insert(
+        "if".to_owned(),
+        Form::Standard(|a, list, _| a.analyze_if(list)),
+    );
+    forms.insert(
+        "loop".to_owned(),
+        Form::Standard(|a, list, _| a.analyze_loop(list)),
+    );
+    forms.insert(
+        "recur".to_owned(),
+        Form::Standard(|a, list, _| {
+            if a.loop_arity.is_none() {
+                return Err(EvalError::RecurOutsideLoop);
+            }
+            Ok(Expr::Recur(a.analyze_all(list.iter().skip(1))?))
+        }),
+    );
+    forms
+}
+
+/// Analyzes `ast` for evaluation in `env`. Locals of `env` and its parents
+/// are resolved as well, so forms can be analyzed inside a running function.
+pub fn analyze(ast: &MalVal, env: &Environment) -> EvalResult<Expr> {
+    let frames = env
+        .scope()
+        .into_iter()
+        .map(|names| Frame {
+            names: names.to_vec(),
+            local: false,
+        })
+        .collect();
+    Analyzer {
+        env,
+        frames,
+        loop_arity: None,
+    }
+    .analyze(ast)
+}
+
+struct Frame {
+    names: Vec<Symbol>,
+    /// Whether the frame is being created by the form under analysis, in
+    /// which case `def!` can add slots to it.
+    local: bool,
+}
+
+/// Resolves the variables of a form as it is turned into an `Expr`.
+pub struct Analyzer<'a> {
+    env: &'a Environment,
+    frames: Vec<Frame>,
+    /// The number of bindings of the loop that a `recur` would return to,
+    /// or `None` when it is not inside a loop in the current function.
+    loop_arity: Option<usize>,
+}
+
+impl Analyzer<'_> {
+    fn analyze(&mut self, ast: &MalVal) -> EvalResult<Expr> {
+        match ast {
+            MalVal::Atom(MalAtom::Sym(sym)) => Ok(self.resolve(*sym)),
+            MalVal::List(list, meta) => {
+                let head = match list.front() {
+                    None => return Ok(Expr::Const(ast.clone())),
+                    Some(MalVal::Atom(MalAtom::Sym(head))) => Some(*head),
+                    Some(_) => None,
+                };
+                match head.and_then(|h| Some((h, self.env.special_form(h)?))) {
+                    Some((_, Form::Standard(analyze))) => analyze(self, list, meta),
+                    Some((head, Form::Custom(_))) => Ok(Expr::Special(head, list.clone())),
+                    None => Ok(Expr::Call {
+                        form: ast.clone(),
+                        head,
+                        items: self.analyze_all(list.iter())?,
+                    }),
+                }
+            }
+            MalVal::Vector(seq, meta) => {
+                Ok(Expr::Vector(self.analyze_all(seq.iter())?, meta.clone()))
+            }
+            MalVal::AssocArray(map, meta) => {
+                let entries = map
+                    .iter()
+                    .map(|(k, v)| Ok((self.analyze(k)?, self.analyze(v)?)))
+                    .collect::<EvalResult<_>>()?;
+                Ok(Expr::Map(entries, meta.clone()))
+            }
+            MalVal::Set(set, meta) => Ok(Expr::Set(self.analyze_all(set.iter())?, meta.clone())),
+            _ => Ok(Expr::Const(ast.clone())),
+        }
+    }
+
+    fn analyze_all<'v, I>(&mut self, forms: I) -> EvalResult<Vec<Expr>>
+    where
+        I: Iterator<Item = &'v MalVal>,
+    {
+        forms.map(|v| self.analyze(v)).collect()
+    }
+
+    fn resolve(&self, sym: Symbol) -> Expr {
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            if let Some(slot) = frame.names.iter().rposition(|&name| name == sym) {
+                return Expr::Local { depth, slot };
+            }
+        }
+        Expr::Global(sym)
+    }
+
+    /// Analyzes `body` in a new frame whose first slots are `names`, and
+    /// returns it along with the names of all slots in the frame.
+    fn analyze_in_frame<F>(
+        &mut self,
+        names: Vec<Symbol>,
+        body: F,
+    ) -> EvalResult<(Rc<[Symbol]>, Expr)>
+    where
+        F: FnOnce(&mut Self) -> EvalResult<Expr>,
+    {
+        self.frames.push(Frame { names, local: true });
+        let result = body(self);
+        let frame = self.frames.pop().unwrap();
+        Ok((frame.names.into(), result?))
+    }
+
+    /// Runs `f` with `arity` as the arity of the loop `recur` returns to.
+    fn in_loop<T, F>(&mut self, arity: Option<usize>, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let outer = std::mem::replace(&mut self.loop_arity, arity);
+        let result = f(self);
+        self.loop_arity = outer;
+        result
+    }
+
+    fn analyze_def(&mut self, list: &Seq) -> EvalResult<Expr> {
+        if list.len() != 3 {
+            return Err(EvalError::InvalidArgs);
+        }
+        let sym = match &list[1] {
+            MalVal::Atom(MalAtom::Sym(sym)) => *sym,
+            _ => return Err(EvalError::NotASymbol),
+        };
+        // The name is declared first so that the value can refer to it,
+        // e.g. to define a recursive function. Until the definition has run
+        // the slot is unset and reads fall back to a lookup by name.
+        let slot = match self.frames.last_mut() {
+            Some(frame) => match frame.names.iter().rposition(|&name| name == sym) {
+                Some(slot) => Some(slot),
+                None if frame.local => {
+                    frame.names.push(sym);
+                    Some(frame.names.len() - 1)
+                }
+                None => None,
+            },
+            None => None,
+        };
+        let value = Box::new(self.analyze(&list[2])?);
+        Ok(Expr::Def { sym, slot, value })
+    }
+
+    fn analyze_let(&mut self, list: &Seq) -> EvalResult<Expr> {
+        if list.len() != 3 {
+            return Err(EvalError::InvalidArgs);
+        }
+        let vars = match &list[1] {
+            MalVal::List(vars, _) => vars,
+            _ => return Err(EvalError::NotAList),
+        };
+        if !vars.len().is_multiple_of(2) {
+            return Err(EvalError::InvalidArgs);
+        }
+        let mut inits = Vec::new();
+        let (names, body) = self.analyze_in_frame(Vec::new(), |a| {
+            a.analyze_bindings(vars, &mut inits)?;
+            a.analyze(&list[2])
+        })?;
+        Ok(Expr::Let {
+            names,
+            inits,
+            body: Box::new(body),
+        })
+    }
+
+    /// Analyzes `let*`-style bindings into the current frame. Each name is
+    /// declared before its initializer is analyzed, so that a function can
+    /// refer to the binding it is stored in. Reads of a binding before it
+    /// is assigned fall back to a lookup by name, which finds any earlier
+    /// binding of the same name.
+    fn analyze_bindings(&mut self, vars: &Seq, inits: &mut Vec<Expr>) -> EvalResult<()> {
+        for (sym, to_eval) in vars.iter().tuples() {
+            match sym {
+                MalVal::Atom(MalAtom::Sym(sym)) => {
+                    self.frames.last_mut().unwrap().names.push(*sym);
+                    inits.push(self.analyze(to_eval)?);
+                }
+                _ => return Err(EvalError::NotASymbol),
+            }
+        }
+        Ok(())
+    }
+
+    fn analyze_fn(&mut self, list: &Seq, meta: &Meta) -> EvalResult<Expr> {
+        let doc = match (list.len(), list.get(2)) {
+            (3, _) => None,
+            (4, Some(MalVal::Atom(MalAtom::Str(doc)))) => Some(doc.clone()),
+            _ => return Err(EvalError::InvalidArgs),
+        };
+        let vars = match &list[1] {
+            MalVal::List(vars, _) => vars,
+            _ => return Err(EvalError::NotAList),
+        };
+        let binds = vars
+            .iter()
+            .map(|v| match v {
+                MalVal::Atom(MalAtom::Sym(sym)) => Ok(*sym),
+                _ => Err(EvalError::NotASymbol),
+            })
+            .collect::<EvalResult<Vec<_>>>()?;
+        let body = list.back().unwrap();
+        let (names, code) =
+            self.analyze_in_frame(binds.clone(), |a| a.in_loop(None, |a| a.analyze(body)))?;
+        Ok(Expr::Fn(Rc::new(Lambda {
+            binds,
+            names,
+            body: body.clone(),
+            code: Rc::new(code),
+            doc,
+            span: meta.span(),
+        })))
+    }
+
+    fn analyze_if(&mut self, list: &Seq) -> EvalResult<Expr> {
+        if list.len() < 3 {
+            return Err(EvalError::InvalidArgs);
+        }
+        Ok(Expr::If {
+            cond: Box::new(self.analyze(&list[1])?),
+            then: Box::new(self.analyze(&list[2])?),
+            otherwise: match list.get(3) {
+                Some(otherwise) => Some(Box::new(self.analyze(otherwise)?)),
+                None => None,
+            },
+        })
+    }
+
+    fn analyze_loop(&mut self, list: &Seq) -> EvalResult<Expr> {
+        if list.len() != 3 {
+            return Err(EvalError::InvalidArgs);
+        }
+        let vars = match &list[1] {
+            MalVal::List(vars, _) | MalVal::Vector(vars, _) => vars,
+            _ => return Err(EvalError::NotAList),
+        };
+        if !vars.len().is_multiple_of(2) {
+            return Err(EvalError::InvalidArgs);
+        }
+        check_recur(&list[2], Some(vars.len() / 2), true)?;
+
+        let mut inits = Vec::new();
+        let (names, body) = self.analyze_in_frame(Vec::new(), |a| {
+            a.analyze_bindings(vars, &mut inits)?;
+            a.in_loop(Some(vars.len() / 2), |a| a.analyze(&list[2]))
+        })?;
+        Ok(Expr::Loop {
+            names,
+            inits,
+            body: Box::new(body),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::env::EnvironmentBuilder;
+
+    fn analyze_str(input: &str) -> Expr {
+        let env = EnvironmentBuilder::new().build();
+        let ast = crate::reader::read_str(input).unwrap().remove(0);
+        analyze(&ast, &env).unwrap()
+    }
+
+    fn lambda_code(expr: Expr) -> Rc<Expr> {
+        match expr {
+            Expr::Fn(lambda) => lambda.code.clone(),
+            _ => panic!("expected a lambda, got {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(analyze_str("x"), Expr::Global("x".into()));
+
+        let code = lambda_code(analyze_str("(fn* (a b) b)"));
+        assert_eq!(*code, Expr::Local { depth: 0, slot: 1 });
+
+        let code = lambda_code(analyze_str("(fn* (a) (fn* (b) a))"));
+        let code = lambda_code((*code).clone());
+        assert_eq!(*code, Expr::Local { depth: 1, slot: 0 });
+
+        // Later bindings shadow earlier ones of the same name.
+        match analyze_str("(let* (a 1 a 2) a)") {
+            Expr::Let { names, body, .. } => {
+                assert_eq!(names.len(), 2);
+                assert_eq!(*body, Expr::Local { depth: 0, slot: 1 });
+            }
+            expr => panic!("expected let*, got {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_def_in_frame() {
+        // def! at the top level defines a global.
+        match analyze_str("(def! a 1)") {
+            Expr::Def { slot, .. } => assert_eq!(slot, None),
+            expr => panic!("expected def!, got {:?}", expr),
+        }
+
+        // Inside a function it adds a slot to the call frame.
+        match analyze_str("(fn* (a) (do (def! b a) b))") {
+            Expr::Fn(lambda) => {
+                assert_eq!(*lambda.names, [Symbol::from("a"), Symbol::from("b")]);
+                match &*lambda.code {
+                    Expr::Do(forms) => {
+                        assert_eq!(forms[1], Expr::Local { depth: 0, slot: 1 })
+                    }
+                    expr => panic!("expected do, got {:?}", expr),
+                }
+            }
+            expr => panic!("expected a lambda, got {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_recur_outside_loop() {
+        let env = EnvironmentBuilder::new().build();
+        for input in &[
+            "(recur 1)",
+            "(fn* (a) (recur))",
+            "(loop [a 1] (fn* () (recur 1)))",
+        ] {
+            let ast = crate::reader::read_str(input).unwrap().remove(0);
+            assert_eq!(
+                analyze(&ast, &env),
+                Err(EvalError::RecurOutsideLoop),
+                "{}",
+                input
+            );
+        }
+    }
+}
@@ -1,20 +1,121 @@
-use crate::types::{EvalError, EvalResult, MalAtom, MalVal, NativeFn};
-use std::collections::HashMap;
+use crate::{
+    printer,
+    reader::{self, Mode},
+    types::{
+        convert::{FromMal, IntoMal, IntoNativeFn},
+        EvalError, EvalResult, MalAtom, MalVal, Meta, NativeFn, Set,
+    },
+};
+use itertools::Itertools;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+};
+
+mod io;
+#[cfg(feature = "json")]
+mod json;
+mod regex;
+mod string;
 
 pub fn defaults() -> HashMap<String, NativeFn> {
+    table()
+        .into_iter()
+        .map(|(name, f, _)| (name.to_owned(), f))
+        .collect()
+}
+
+/// Names of the builtins in `defaults` that the optimizer may evaluate
+/// ahead of time.
+pub fn pure() -> HashSet<String> {
+    table()
+        .into_iter()
+        .filter(|(_, _, pure)| *pure)
+        .map(|(name, _, _)| name.to_owned())
+        .collect()
+}
+
+/// The builtins in `defaults`, each with whether it is pure: given the
+/// same arguments it returns the same result and has no other effect.
+fn table() -> Vec<(&'static str, NativeFn, bool)> {
+    #[cfg_attr(not(feature = "json"), allow(unused_mut))]
+    let mut t = vec![
+        ("+", add.into_native_fn(), true),
+        ("-", sub.into_native_fn(), true),
+        ("*", mul.into_native_fn(), true),
+        ("=", eq.into_native_fn(), true),
+        (">", gt.into_native_fn(), true),
+        (">=", gte.into_native_fn(), true),
+        ("<", lt.into_native_fn(), true),
+        ("<=", lte.into_native_fn(), true),
+        ("list", list.into_native_fn(), true),
+        ("list?", is_list.into_native_fn(), true),
+        ("empty?", is_empty.into_native_fn(), true),
+        ("count", count.into_native_fn(), true),
+        ("doc", doc.into_native_fn(), true),
+        ("with-meta", with_meta.into_native_fn(), true),
+        ("meta", meta.into_native_fn(), true),
+        ("vector", vector.into_native_fn(), true),
+        ("hash-map", hash_map.into_native_fn(), true),
+        ("cons", cons.into_native_fn(), true),
+        ("conj", conj.into_native_fn(), true),
+        ("assoc", assoc.into_native_fn(), true),
+        ("dissoc", dissoc.into_native_fn(), true),
+        ("get", get.into_native_fn(), true),
+        ("set", set.into_native_fn(), true),
+        ("set?", is_set.into_native_fn(), true),
+        ("disj", disj.into_native_fn(), true),
+        ("contains?", contains.into_native_fn(), true),
+        ("union", union.into_native_fn(), true),
+        ("intersection", intersection.into_native_fn(), true),
+        ("difference", difference.into_native_fn(), true),
+        ("char", to_char.into_native_fn(), true),
+        ("char?", is_char.into_native_fn(), true),
+        ("int->char", int_to_char.into_native_fn(), true),
+        ("char->int", char_to_int.into_native_fn(), true),
+        ("seq", seq.into_native_fn(), true),
+        ("subs", string::subs.into_native_fn(), true),
+        ("str/split", string::split.into_native_fn(), true),
+        ("str/join", string::join.into_native_fn(), true),
+        ("str/trim", string::trim.into_native_fn(), true),
+        ("str/upper-case", string::upper_case.into_native_fn(), true),
+        ("str/lower-case", string::lower_case.into_native_fn(), true),
+        ("str/replace", string::replace.into_native_fn(), true),
+        (
+            "str/starts-with?",
+            string::starts_with.into_native_fn(),
+            true,
+        ),
+        ("str/index-of", string::index_of.into_native_fn(), true),
+        ("str/format", string::format.into_native_fn(), true),
+        ("re-pattern", regex::re_pattern.into_native_fn(), true),
+        ("re-find", regex::re_find.into_native_fn(), true),
+        ("re-matches", regex::re_matches.into_native_fn(), true),
+        ("re-seq", regex::re_seq.into_native_fn(), true),
+        ("read-edn", read_edn.into_native_fn(), true),
+        ("pr-edn", pr_edn.into_native_fn(), true),
+    ];
+    #[cfg(feature = "json")]
+    t.extend(vec![
+        ("json-parse", json::parse.into_native_fn(), true),
+        ("json-stringify", json::stringify.into_native_fn(), true),
+    ]);
+    t
+}
+
+/// Builtins with effects outside the interpreter: reading and writing
+/// files, environment variables and subprocesses. These are not part of
+/// `defaults`; see `EnvironmentBuilder::with_io`.
+pub fn io() -> HashMap<String, NativeFn> {
     let mut h: HashMap<String, NativeFn> = HashMap::new();
-    h.insert("+".to_owned(), add);
-    h.insert("-".to_owned(), sub);
-    h.insert("*".to_owned(), mul);
-    h.insert("=".to_owned(), eq);
-    h.insert(">".to_owned(), gt);
-    h.insert(">=".to_owned(), gte);
-    h.insert("<".to_owned(), lt);
-    h.insert("<=".to_owned(), lte);
-    h.insert("list".to_owned(), list);
-    h.insert("list?".to_owned(), is_list);
-    h.insert("empty?".to_owned(), is_empty);
-    h.insert("count".to_owned(), count);
+    h.insert("slurp".to_owned(), io::slurp.into_native_fn());
+    h.insert("spit".to_owned(), io::spit.into_native_fn());
+    h.insert("file-exists?".to_owned(), io::file_exists.into_native_fn());
+    h.insert("list-dir".to_owned(), io::list_dir.into_native_fn());
+    h.insert("mkdir".to_owned(), io::mkdir.into_native_fn());
+    h.insert("delete-file".to_owned(), io::delete_file.into_native_fn());
+    h.insert("getenv".to_owned(), io::getenv.into_native_fn());
+    h.insert("sh".to_owned(), io::sh.into_native_fn());
     h
 }
 
@@ -76,15 +177,8 @@ fn eq(args: Vec<MalVal>) -> EvalResult<MalVal> {
 
 macro_rules! def_int_op {
     ($name:ident, $op:tt) => {
-        fn $name(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
-            if args.len() != 2 {
-                Err(EvalError::InvalidArgs)
-            } else {
-                let arg0 = into_int(args.remove(0))?;
-                let arg1 = into_int(args.remove(0))?;
-
-                Ok(MalVal::Atom((arg0 $op arg1).into()))
-            }
+        fn $name(a: i64, b: i64) -> EvalResult<bool> {
+            Ok(a $op b)
         }
     };
 }
@@ -94,56 +188,365 @@ def_int_op!(gte, >=);
 def_int_op!(lt, <);
 def_int_op!(lte, <=);
 
-#[allow(clippy::clippy::unnecessary_wraps)]
+#[allow(clippy::unnecessary_wraps)]
 fn list(args: Vec<MalVal>) -> EvalResult<MalVal> {
-    Ok(MalVal::List(args))
+    Ok(MalVal::list(args))
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn vector(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    Ok(MalVal::vector(args))
+}
+
+/// `(hash-map k v ...)`. A repeated key keeps its last value.
+fn hash_map(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if !args.len().is_multiple_of(2) {
+        Err(EvalError::InvalidArgs)
+    } else {
+        Ok(MalVal::assoc_array(args.into_iter().tuples()))
+    }
+}
+
+fn coll_len(v: &MalVal) -> EvalResult<usize> {
+    match v {
+        MalVal::List(seq, _) | MalVal::Vector(seq, _) => Ok(seq.len()),
+        MalVal::AssocArray(map, _) => Ok(map.len()),
+        MalVal::Set(set, _) => Ok(set.len()),
+        MalVal::Atom(MalAtom::Nil) => Ok(0),
+        _ => Err(EvalError::NotAList),
+    }
 }
 
-fn count(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+fn count(args: Vec<MalVal>) -> EvalResult<MalVal> {
     if args.len() != 1 {
         Err(EvalError::InvalidArgs)
-    } else if let MalVal::List(list) = args.remove(0) {
-        Ok(MalVal::Atom(MalAtom::Int(list.len() as i64)))
     } else {
-        Err(EvalError::NotAList)
+        Ok(MalVal::Atom(MalAtom::Int(coll_len(&args[0])? as i64)))
     }
 }
 
-fn is_empty(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+fn is_empty(args: Vec<MalVal>) -> EvalResult<MalVal> {
     if args.len() != 1 {
         Err(EvalError::InvalidArgs)
-    } else if let MalVal::List(list) = args.remove(0) {
-        if list.is_empty() {
-            Ok(MalVal::Atom(MalAtom::True))
-        } else {
-            Ok(MalVal::Atom(MalAtom::False))
-        }
     } else {
-        Err(EvalError::NotAList)
+        Ok(MalVal::Atom((coll_len(&args[0])? == 0).into()))
+    }
+}
+
+fn cons(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.len() != 2 {
+        return Err(EvalError::InvalidArgs);
+    }
+    let head = args.remove(0);
+    let mut seq = match args.remove(0) {
+        MalVal::List(seq, _) | MalVal::Vector(seq, _) => seq,
+        MalVal::Atom(MalAtom::Nil) => Default::default(),
+        _ => return Err(EvalError::NotAList),
+    };
+    seq.push_front(head);
+    Ok(MalVal::List(seq, Meta::default()))
+}
+
+/// Adds items where they are cheapest for the collection: at the front of
+/// lists and at the back of vectors. Maps take `[key value]` vectors.
+fn conj(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.is_empty() {
+        return Err(EvalError::InvalidArgs);
+    }
+    match args.remove(0) {
+        MalVal::List(mut seq, meta) => {
+            for v in args {
+                seq.push_front(v);
+            }
+            Ok(MalVal::List(seq, meta))
+        }
+        MalVal::Vector(mut seq, meta) => {
+            seq.extend(args);
+            Ok(MalVal::Vector(seq, meta))
+        }
+        MalVal::AssocArray(mut map, meta) => {
+            for v in args {
+                match v {
+                    MalVal::Vector(entry, _) if entry.len() == 2 => {
+                        map.insert(entry[0].clone(), entry[1].clone());
+                    }
+                    _ => return Err(EvalError::InvalidArgs),
+                }
+            }
+            Ok(MalVal::AssocArray(map, meta))
+        }
+        MalVal::Set(mut set, meta) => {
+            set.extend(args);
+            Ok(MalVal::Set(set, meta))
+        }
+        _ => Err(EvalError::NotAList),
+    }
+}
+
+fn assoc(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.len().is_multiple_of(2) {
+        return Err(EvalError::InvalidArgs);
+    }
+    match args.remove(0) {
+        MalVal::AssocArray(mut map, meta) => {
+            for (k, v) in args.into_iter().tuples() {
+                map.insert(k, v);
+            }
+            Ok(MalVal::AssocArray(map, meta))
+        }
+        MalVal::Vector(mut seq, meta) => {
+            for (k, v) in args.into_iter().tuples() {
+                let i = i64::from_mal(k)?;
+                if i >= 0 && (i as usize) < seq.len() {
+                    seq.set(i as usize, v);
+                } else if i as usize == seq.len() {
+                    seq.push_back(v);
+                } else {
+                    return Err(EvalError::InvalidArgs);
+                }
+            }
+            Ok(MalVal::Vector(seq, meta))
+        }
+        MalVal::Atom(MalAtom::Nil) => Ok(MalVal::assoc_array(args.into_iter().tuples())),
+        _ => Err(EvalError::InvalidArgs),
+    }
+}
+
+fn dissoc(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.is_empty() {
+        return Err(EvalError::InvalidArgs);
+    }
+    match args.remove(0) {
+        MalVal::AssocArray(mut map, meta) => {
+            for k in args.iter() {
+                map.remove(k);
+            }
+            Ok(MalVal::AssocArray(map, meta))
+        }
+        MalVal::Atom(MalAtom::Nil) => Ok(MalVal::Atom(MalAtom::Nil)),
+        _ => Err(EvalError::InvalidArgs),
+    }
+}
+
+fn get(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.len() != 2 {
+        return Err(EvalError::InvalidArgs);
+    }
+    let found = match (&args[0], &args[1]) {
+        (MalVal::AssocArray(map, _), k) => map.get(k).cloned(),
+        (MalVal::Vector(seq, _), MalVal::Atom(MalAtom::Int(i))) if *i >= 0 => {
+            seq.get(*i as usize).cloned()
+        }
+        (MalVal::Set(set, _), k) if set.contains(k) => Some(k.clone()),
+        (MalVal::Set(..), _) => None,
+        // Tagged literals look like `{:tag tag :form form}`, as in Clojure.
+        (MalVal::Tagged(t), MalVal::Atom(MalAtom::Keyword(k))) => match k.as_str() {
+            "tag" => Some(MalVal::Atom(MalAtom::Sym(t.tag))),
+            "form" => Some(t.form.clone()),
+            _ => None,
+        },
+        (MalVal::Vector(..), _) | (MalVal::Atom(MalAtom::Nil), _) => None,
+        _ => return Err(EvalError::InvalidArgs),
+    };
+    Ok(found.unwrap_or(MalVal::Atom(MalAtom::Nil)))
+}
+
+/// Collects the items of a list, vector or set, or the `[key value]`
+/// entries of a map, into a set.
+fn set(coll: MalVal) -> EvalResult<Set> {
+    match coll {
+        MalVal::List(seq, _) | MalVal::Vector(seq, _) => Ok(seq.into_iter().collect()),
+        MalVal::Set(set, _) => Ok(set),
+        MalVal::AssocArray(map, _) => Ok(map
+            .into_iter()
+            .map(|(k, v)| MalVal::vector(vec![k, v]))
+            .collect()),
+        MalVal::Atom(MalAtom::Nil) => Ok(Set::default()),
+        _ => Err(EvalError::NotAList),
+    }
+}
+
+fn is_set(v: MalVal) -> EvalResult<bool> {
+    Ok(matches!(v, MalVal::Set(..)))
+}
+
+fn disj(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.is_empty() {
+        return Err(EvalError::InvalidArgs);
+    }
+    match args.remove(0) {
+        MalVal::Set(mut set, meta) => {
+            for k in args.iter() {
+                set.remove(k);
+            }
+            Ok(MalVal::Set(set, meta))
+        }
+        MalVal::Atom(MalAtom::Nil) => Ok(MalVal::Atom(MalAtom::Nil)),
+        v => Err(EvalError::UnexpectedType("a set", v.to_string())),
+    }
+}
+
+/// Whether `key` is a member of a set, a key of a map or an index of a
+/// vector. Lists are not indexed and are an error.
+fn contains(coll: MalVal, key: MalVal) -> EvalResult<bool> {
+    match (coll, key) {
+        (MalVal::Set(set, _), k) => Ok(set.contains(&k)),
+        (MalVal::AssocArray(map, _), k) => Ok(map.contains_key(&k)),
+        (MalVal::Vector(seq, _), MalVal::Atom(MalAtom::Int(i))) => {
+            Ok(i >= 0 && (i as usize) < seq.len())
+        }
+        (MalVal::Vector(..), _) | (MalVal::Atom(MalAtom::Nil), _) => Ok(false),
+        _ => Err(EvalError::InvalidArgs),
+    }
+}
+
+fn sets(args: Vec<MalVal>) -> EvalResult<Vec<Set>> {
+    args.into_iter().map(Set::from_mal).collect()
+}
+
+fn union(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    Ok(Set::unions(sets(args)?).into_mal())
+}
+
+fn intersection(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    let mut sets = sets(args)?.into_iter();
+    let first = sets.next().ok_or(EvalError::InvalidArgs)?;
+    Ok(sets.fold(first, Set::intersection).into_mal())
+}
+
+fn difference(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    let mut sets = sets(args)?.into_iter();
+    let first = sets.next().ok_or(EvalError::InvalidArgs)?;
+    Ok(sets.fold(first, Set::relative_complement).into_mal())
+}
+
+/// Converts a code point or a one character string to a character.
+fn to_char(v: MalVal) -> EvalResult<char> {
+    match v {
+        MalVal::Atom(MalAtom::Char(c)) => Ok(c),
+        MalVal::Atom(MalAtom::Int(i)) => int_to_char(i),
+        MalVal::Atom(MalAtom::Str(s)) if s.chars().count() == 1 => Ok(s.chars().next().unwrap()),
+        v => Err(EvalError::UnexpectedType(
+            "a code point or a one character string",
+            v.to_string(),
+        )),
     }
 }
 
-fn is_list(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+fn is_char(v: MalVal) -> EvalResult<bool> {
+    Ok(matches!(v, MalVal::Atom(MalAtom::Char(_))))
+}
+
+fn int_to_char(i: i64) -> EvalResult<char> {
+    u32::try_from(i)
+        .ok()
+        .and_then(std::char::from_u32)
+        .ok_or_else(|| EvalError::Conversion(format!("{} is not a Unicode code point", i)))
+}
+
+fn char_to_int(c: char) -> EvalResult<i64> {
+    Ok(c as i64)
+}
+
+/// The items of a collection as a list: the characters of a string, the
+/// `[key value]` entries of a map. Empty collections give nil.
+fn seq(coll: MalVal) -> EvalResult<MalVal> {
+    let items: Vec<MalVal> = match coll {
+        MalVal::List(seq, _) | MalVal::Vector(seq, _) => seq.into_iter().collect(),
+        MalVal::Set(set, _) => set.into_iter().collect(),
+        MalVal::AssocArray(map, _) => map
+            .into_iter()
+            .map(|(k, v)| MalVal::vector(vec![k, v]))
+            .collect(),
+        MalVal::Atom(MalAtom::Str(s)) => s.chars().map(char::into_mal).collect(),
+        MalVal::Atom(MalAtom::Nil) => vec![],
+        _ => return Err(EvalError::NotAList),
+    };
+    Ok(if items.is_empty() {
+        MalVal::Atom(MalAtom::Nil)
+    } else {
+        MalVal::list(items)
+    })
+}
+
+/// Reads the first form of an EDN string, or nil if there is none. See
+/// `reader::Mode::Edn` for what is supported.
+fn read_edn(s: String) -> EvalResult<MalVal> {
+    let mut forms =
+        reader::read_str_with(&s, Mode::Edn).map_err(|e| EvalError::Edn(e.to_string()))?;
+    Ok(if forms.is_empty() {
+        MalVal::Atom(MalAtom::Nil)
+    } else {
+        forms.swap_remove(0)
+    })
+}
+
+fn pr_edn(v: MalVal) -> EvalResult<String> {
+    printer::pr_edn(&v)
+}
+
+fn is_list(v: MalVal) -> EvalResult<bool> {
+    Ok(matches!(v, MalVal::List(..)))
+}
+
+fn doc(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
     if args.len() != 1 {
         Err(EvalError::InvalidArgs)
-    } else if let MalVal::List(_) = args.remove(0) {
-        Ok(MalVal::Atom(MalAtom::True))
+    } else if let MalVal::Fn(f) = args.remove(0) {
+        let meta_doc = f
+            .meta
+            .get()
+            .and_then(|m| m.get(&MalVal::Atom(MalAtom::Keyword("doc".to_owned()))));
+        let mut text = match (&f.doc, meta_doc) {
+            (Some(doc), _) | (None, Some(MalVal::Atom(MalAtom::Str(doc)))) => {
+                format!("{}\n  {}", f.signature(), doc)
+            }
+            _ => f.signature(),
+        };
+        if let Some(span) = f.span {
+            text.push_str(&format!("\n  defined at {}", span));
+        }
+        Ok(MalVal::Atom(MalAtom::Str(text)))
     } else {
-        Ok(MalVal::Atom(MalAtom::False))
+        Err(EvalError::InvalidArgs)
     }
 }
 
-pub fn into_int(v: MalVal) -> EvalResult<i64> {
-    if let MalVal::Atom(MalAtom::Int(i)) = v {
-        Ok(i)
+fn with_meta(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.len() != 2 {
+        Err(EvalError::InvalidArgs)
     } else {
-        Err(EvalError::NotANumber)
+        let val = args.remove(0);
+        let meta = args.remove(0);
+        val.with_meta(meta).ok_or(EvalError::InvalidArgs)
+    }
+}
+
+fn meta(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.len() != 1 {
+        Err(EvalError::InvalidArgs)
+    } else {
+        Ok(args[0]
+            .meta()
+            .cloned()
+            .unwrap_or(MalVal::Atom(MalAtom::Nil)))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::test_util::{int, kw};
+
+    #[test]
+    fn test_table() {
+        let fns = defaults();
+        assert_eq!(fns.len(), table().len(), "duplicate builtin name");
+        for name in pure() {
+            assert!(fns.contains_key(&name), "{}", name);
+        }
+        assert!(io().keys().all(|name| !fns.contains_key(name)));
+    }
 
     #[test]
     fn test_int_comparisons() {
@@ -169,8 +572,8 @@ mod tests {
             }
             {
                 let v = vec![
-                    MalVal::Atom(MalAtom::Sym("a".to_owned())),
-                    MalVal::Atom(MalAtom::Sym("b".to_owned())),
+                    MalVal::Atom(MalAtom::Sym("a".into())),
+                    MalVal::Atom(MalAtom::Sym("b".into())),
                 ];
                 let res = fns[*op](v).unwrap_err();
                 assert_eq!(res, EvalError::NotANumber);
@@ -180,7 +583,7 @@ mod tests {
 
     #[test]
     fn test_lt() {
-        let f = defaults()["<"];
+        let f = defaults()["<"].clone();
 
         {
             let v = vec![MalVal::Atom(MalAtom::Int(1)), MalVal::Atom(MalAtom::Int(2))];
@@ -201,7 +604,7 @@ mod tests {
 
     #[test]
     fn test_lte() {
-        let f = defaults()["<="];
+        let f = defaults()["<="].clone();
 
         {
             let v = vec![MalVal::Atom(MalAtom::Int(1)), MalVal::Atom(MalAtom::Int(2))];
@@ -222,7 +625,7 @@ mod tests {
 
     #[test]
     fn test_gt() {
-        let f = defaults()[">"];
+        let f = defaults()[">"].clone();
 
         {
             let v = vec![MalVal::Atom(MalAtom::Int(1)), MalVal::Atom(MalAtom::Int(2))];
@@ -243,7 +646,7 @@ mod tests {
 
     #[test]
     fn test_gte() {
-        let f = defaults()[">="];
+        let f = defaults()[">="].clone();
 
         {
             let v = vec![MalVal::Atom(MalAtom::Int(1)), MalVal::Atom(MalAtom::Int(2))];
@@ -261,4 +664,106 @@ mod tests {
             assert_eq!(res, MalVal::Atom(MalAtom::True));
         }
     }
+
+    #[test]
+    fn test_collections() {
+        let fns = defaults();
+
+        {
+            let res = fns["cons"](vec![int(1), MalVal::vector(vec![int(2)])]).unwrap();
+            assert_eq!(res, MalVal::list(vec![int(1), int(2)]));
+        }
+        {
+            let res = fns["cons"](vec![int(1), MalVal::Atom(MalAtom::Nil)]).unwrap();
+            assert_eq!(res, MalVal::list(vec![int(1)]));
+        }
+        {
+            let res = fns["cons"](vec![int(1), int(2)]).unwrap_err();
+            assert_eq!(res, EvalError::NotAList);
+        }
+        {
+            let res = fns["conj"](vec![MalVal::vector(vec![int(1)]), int(2)]).unwrap();
+            assert_eq!(res, MalVal::vector(vec![int(1), int(2)]));
+        }
+        {
+            let res = fns["conj"](vec![MalVal::list(vec![int(1)]), int(2)]).unwrap();
+            assert_eq!(res, MalVal::list(vec![int(2), int(1)]));
+        }
+        {
+            let res = fns["assoc"](vec![MalVal::assoc_array(vec![]), kw("a"), int(1)]).unwrap();
+            assert_eq!(res, MalVal::assoc_array(vec![(kw("a"), int(1))]));
+        }
+        {
+            let res = fns["assoc"](vec![MalVal::assoc_array(vec![]), kw("a")]).unwrap_err();
+            assert_eq!(res, EvalError::InvalidArgs);
+        }
+        {
+            let res = fns["assoc"](vec![MalVal::vector(vec![int(1)]), int(5), int(2)]).unwrap_err();
+            assert_eq!(res, EvalError::InvalidArgs);
+        }
+        {
+            let m = MalVal::assoc_array(vec![(kw("a"), int(1)), (kw("b"), int(2))]);
+            let res = fns["dissoc"](vec![m, kw("a")]).unwrap();
+            assert_eq!(res, MalVal::assoc_array(vec![(kw("b"), int(2))]));
+        }
+        {
+            let m = MalVal::assoc_array(vec![(kw("a"), int(1))]);
+            assert_eq!(fns["get"](vec![m.clone(), kw("a")]).unwrap(), int(1));
+            assert_eq!(
+                fns["get"](vec![m, kw("b")]).unwrap(),
+                MalVal::Atom(MalAtom::Nil)
+            );
+        }
+        {
+            let res = fns["hash-map"](vec![kw("a")]).unwrap_err();
+            assert_eq!(res, EvalError::InvalidArgs);
+            // Unlike a map literal, later entries replace earlier ones.
+            let res = fns["hash-map"](vec![kw("a"), int(1), kw("a"), int(2)]).unwrap();
+            assert_eq!(res, MalVal::assoc_array(vec![(kw("a"), int(2))]));
+        }
+        {
+            let res = fns["count"](vec![MalVal::assoc_array(vec![(kw("a"), int(1))])]).unwrap();
+            assert_eq!(res, int(1));
+            let res = fns["empty?"](vec![MalVal::vector(vec![])]).unwrap();
+            assert_eq!(res, MalVal::Atom(MalAtom::True));
+        }
+    }
+
+    #[test]
+    fn test_edn() {
+        let fns = defaults();
+        let read = |s: &str| fns["read-edn"](vec![MalVal::Atom(MalAtom::Str(s.to_owned()))]);
+        let tagged = read("#inst \"2021-03-04\"").unwrap();
+        assert_eq!(
+            fns["get"](vec![
+                tagged.clone(),
+                MalVal::Atom(MalAtom::Keyword("tag".into()))
+            ]),
+            Ok(MalVal::Atom(MalAtom::Sym("inst".into())))
+        );
+        assert_eq!(
+            fns["pr-edn"](vec![tagged]),
+            Ok(MalVal::Atom(MalAtom::Str("#inst \"2021-03-04\"".into())))
+        );
+        assert_eq!(read(" "), Ok(MalVal::Atom(MalAtom::Nil)));
+        assert!(matches!(read("#{"), Err(EvalError::Edn(_))));
+        assert_eq!(
+            read("`x"),
+            Err(EvalError::Edn("Unexpected token `".to_owned()))
+        );
+        assert_eq!(
+            read("1.5"),
+            Err(EvalError::Edn(
+                "Floating-point numbers are not supported: 1.5".to_owned()
+            ))
+        );
+        assert_eq!(
+            read("-9223372036854775808"),
+            Ok(MalVal::Atom(MalAtom::Int(i64::MIN)))
+        );
+        assert!(matches!(
+            read("12345678901234567890N"),
+            Err(EvalError::Edn(_))
+        ));
+    }
 }
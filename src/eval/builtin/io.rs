@@ -0,0 +1,168 @@
+//! Builtins with effects outside the interpreter: files, environment
+//! variables and subprocesses. They are only registered when the
+//! environment is built with `EnvironmentBuilder::with_io`.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    process::Command,
+};
+
+use super::string::to_str;
+use crate::types::{convert::FromMal, EvalError, EvalResult, MalAtom, MalVal};
+
+fn io_error(path: &str, e: std::io::Error) -> EvalError {
+    EvalError::Io(format!("{}: {}", path, e))
+}
+
+pub fn slurp(path: String) -> EvalResult<String> {
+    fs::read_to_string(&path).map_err(|e| io_error(&path, e))
+}
+
+/// `(spit path content & opts)` writes `content`, as `str` would print it,
+/// to the file at `path`. With `:append true` it is added to the end of
+/// the file rather than replacing it.
+pub fn spit(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.len() != 2 && args.len() != 4 {
+        return Err(EvalError::InvalidArgs);
+    }
+    let mut args = args.into_iter();
+    let path = String::from_mal(args.next().unwrap())?;
+    let content = to_str(&args.next().unwrap());
+    let append = match (args.next(), args.next()) {
+        (Some(MalVal::Atom(MalAtom::Keyword(k))), Some(v)) if k == "append" => v.is_truthy(),
+        (None, None) => false,
+        _ => return Err(EvalError::InvalidArgs),
+    };
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(&path)
+        .and_then(|mut f| f.write_all(content.as_bytes()))
+        .map_err(|e| io_error(&path, e))?;
+    Ok(MalVal::Atom(MalAtom::Nil))
+}
+
+pub fn file_exists(path: String) -> EvalResult<bool> {
+    Ok(Path::new(&path).exists())
+}
+
+/// `(list-dir path)`, the names of the entries in a directory, sorted.
+pub fn list_dir(path: String) -> EvalResult<Vec<String>> {
+    let mut names = fs::read_dir(&path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<std::io::Result<Vec<_>>>()
+        })
+        .map_err(|e| io_error(&path, e))?;
+    names.sort();
+    Ok(names)
+}
+
+/// `(mkdir path)` creates a directory along with any missing parents.
+pub fn mkdir(path: String) -> EvalResult<()> {
+    fs::create_dir_all(&path).map_err(|e| io_error(&path, e))
+}
+
+/// `(delete-file path)` removes a file or an empty directory.
+pub fn delete_file(path: String) -> EvalResult<()> {
+    let result = if Path::new(&path).is_dir() {
+        fs::remove_dir(&path)
+    } else {
+        fs::remove_file(&path)
+    };
+    result.map_err(|e| io_error(&path, e))
+}
+
+/// `(getenv name)`, the value of an environment variable, or nil if it is
+/// unset or not valid Unicode.
+pub fn getenv(name: String) -> EvalResult<Option<String>> {
+    Ok(std::env::var(name).ok())
+}
+
+/// `(sh cmd args...)` runs `cmd` without a shell and waits for it to exit.
+/// Returns `{:exit code :out stdout :err stderr}`; the exit code is nil if
+/// the process was killed by a signal.
+///
+/// The wait is not interruptible: while the process runs, neither the
+/// step or time limit nor the interrupt flag is checked.
+pub fn sh(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    let mut args = args.into_iter().map(String::from_mal);
+    let cmd = args.next().ok_or(EvalError::InvalidArgs)??;
+    let args = args.collect::<EvalResult<Vec<_>>>()?;
+    let output = Command::new(&cmd)
+        .args(&args)
+        .output()
+        .map_err(|e| io_error(&cmd, e))?;
+    let kw = |k: &str| MalVal::Atom(MalAtom::Keyword(k.to_owned()));
+    let text = |bytes: &[u8]| MalVal::Atom(MalAtom::Str(String::from_utf8_lossy(bytes).into()));
+    Ok(MalVal::assoc_array(vec![
+        (
+            kw("exit"),
+            MalVal::Atom(
+                output
+                    .status
+                    .code()
+                    .map_or(MalAtom::Nil, |c| MalAtom::Int(c.into())),
+            ),
+        ),
+        (kw("out"), text(&output.stdout)),
+        (kw("err"), text(&output.stderr)),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::test_util::{int, kw, string};
+
+    #[test]
+    fn test_files() {
+        let dir = std::env::temp_dir().join(format!("mal-io-test-{}", std::process::id()));
+        let dir = dir.to_str().unwrap().to_owned();
+        let file = format!("{}/sub/a.txt", dir);
+
+        mkdir(format!("{}/sub", dir)).unwrap();
+        spit(vec![string(&file), string("one ")]).unwrap();
+        spit(vec![
+            string(&file),
+            int(2),
+            kw("append"),
+            MalVal::Atom(MalAtom::True),
+        ])
+        .unwrap();
+        assert_eq!(slurp(file.clone()), Ok("one 2".to_owned()));
+        assert_eq!(file_exists(file.clone()), Ok(true));
+        assert_eq!(
+            list_dir(format!("{}/sub", dir)),
+            Ok(vec!["a.txt".to_owned()])
+        );
+
+        delete_file(file.clone()).unwrap();
+        assert_eq!(file_exists(file.clone()), Ok(false));
+        assert!(matches!(slurp(file), Err(EvalError::Io(_))));
+        delete_file(format!("{}/sub", dir)).unwrap();
+        delete_file(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sh() {
+        assert_eq!(
+            sh(vec![string("sh"), string("-c"), string("echo hi; exit 3")]),
+            Ok(MalVal::assoc_array(vec![
+                (kw("exit"), int(3)),
+                (kw("out"), string("hi\n")),
+                (kw("err"), string("")),
+            ]))
+        );
+        assert!(matches!(
+            sh(vec![string("mal-no-such-command")]),
+            Err(EvalError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+//! JSON builtins, converting through the serde impls of `MalVal`.
+
+use crate::types::{EvalError, EvalResult, MalAtom, MalVal, Meta};
+
+/// `(json-parse s)` reads the JSON text `s`. Objects become hash maps with
+/// string keys, or keyword keys if a truthy second argument is given, and
+/// arrays become vectors.
+pub fn parse(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+    let keywordize = match args.len() {
+        1 => false,
+        2 => args.pop().unwrap().is_truthy(),
+        _ => return Err(EvalError::InvalidArgs),
+    };
+    let text = match args.pop().unwrap() {
+        MalVal::Atom(MalAtom::Str(s)) => s,
+        v => return Err(EvalError::UnexpectedType("a string", v.to_string())),
+    };
+    let v = serde_json::from_str(&text).map_err(|e| EvalError::Json(e.to_string()))?;
+    Ok(if keywordize { keywordize_keys(v) } else { v })
+}
+
+/// `(json-stringify v)` writes `v` as JSON. Keywords and symbols are
+/// written as strings, and map keys must be strings or keywords.
+pub fn stringify(v: MalVal) -> EvalResult<String> {
+    serde_json::to_string(&v).map_err(|e| EvalError::Conversion(e.to_string()))
+}
+
+fn keywordize_keys(v: MalVal) -> MalVal {
+    match v {
+        MalVal::Vector(seq, _) => MalVal::Vector(
+            seq.into_iter().map(keywordize_keys).collect(),
+            Meta::default(),
+        ),
+        MalVal::AssocArray(map, _) => MalVal::AssocArray(
+            map.into_iter()
+                .map(|(k, v)| match k {
+                    MalVal::Atom(MalAtom::Str(s)) => {
+                        (MalVal::Atom(MalAtom::Keyword(s)), keywordize_keys(v))
+                    }
+                    k => (k, keywordize_keys(v)),
+                })
+                .collect(),
+            Meta::default(),
+        ),
+        v => v,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reader::read_str, types::test_util::string};
+
+    fn read(input: &str) -> MalVal {
+        read_str(input).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = string(r#"{"a": [1, true, null, "x"], "b": {"c": -2}}"#);
+        assert_eq!(
+            parse(vec![text.clone()]),
+            Ok(read(r#"{"a" [1 true nil "x"] "b" {"c" -2}}"#))
+        );
+        assert_eq!(
+            parse(vec![text, MalVal::Atom(MalAtom::True)]),
+            Ok(read(r#"{:a [1 true nil "x"] :b {:c -2}}"#))
+        );
+        assert!(matches!(
+            parse(vec![string("[1,")]),
+            Err(EvalError::Json(_))
+        ));
+        assert!(matches!(
+            parse(vec![string("1.5")]),
+            Err(EvalError::Json(_))
+        ));
+        assert_eq!(parse(vec![]), Err(EvalError::InvalidArgs));
+    }
+
+    #[test]
+    fn test_stringify() {
+        assert_eq!(
+            stringify(read(r#"(1 "a\"" nil [true {:k false}])"#)),
+            Ok(r#"[1,"a\"",null,[true,{"k":false}]]"#.to_owned())
+        );
+        assert_eq!(stringify(read("{1 2}")), Ok(r#"{"1":2}"#.to_owned()));
+    }
+}
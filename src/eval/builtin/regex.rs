@@ -0,0 +1,98 @@
+//! Regex builtins. A match is returned as the matched string, or as a
+//! vector of the match followed by its capture groups if the regex has
+//! any, with nil for groups that did not take part.
+
+use regex::Captures;
+
+use crate::types::{EvalError, EvalResult, MalAtom, MalVal, Regex};
+
+fn matched(caps: &Captures) -> MalVal {
+    let string = |m: Option<regex::Match>| {
+        m.map_or(MalVal::Atom(MalAtom::Nil), |m| {
+            MalVal::Atom(MalAtom::Str(m.as_str().to_owned()))
+        })
+    };
+    if caps.len() == 1 {
+        string(caps.get(0))
+    } else {
+        MalVal::vector(caps.iter().map(string).collect::<Vec<_>>())
+    }
+}
+
+/// `(re-pattern s)` compiles `s` to a regex. A regex is returned as is.
+pub fn re_pattern(v: MalVal) -> EvalResult<Regex> {
+    match v {
+        MalVal::Regex(re) => Ok(re),
+        MalVal::Atom(MalAtom::Str(s)) => regex::Regex::new(&s)
+            .map(Regex)
+            .map_err(|e| EvalError::Regex(e.to_string())),
+        v => Err(EvalError::UnexpectedType("a string", v.to_string())),
+    }
+}
+
+/// `(re-find re s)`, the first match of `re` in `s`, or nil.
+pub fn re_find(re: Regex, s: String) -> EvalResult<MalVal> {
+    Ok(re
+        .0
+        .captures(&s)
+        .map_or(MalVal::Atom(MalAtom::Nil), |caps| matched(&caps)))
+}
+
+/// `(re-matches re s)`, the match of `re` against the whole of `s`, or nil.
+pub fn re_matches(re: Regex, s: String) -> EvalResult<MalVal> {
+    let whole = regex::Regex::new(&format!(r"\A(?:{})\z", re.0.as_str()))
+        .map_err(|e| EvalError::Regex(e.to_string()))?;
+    re_find(Regex(whole), s)
+}
+
+/// `(re-seq re s)`, a list of the successive matches of `re` in `s`, or nil
+/// if there are none.
+pub fn re_seq(re: Regex, s: String) -> EvalResult<MalVal> {
+    let matches: Vec<MalVal> = re.0.captures_iter(&s).map(|caps| matched(&caps)).collect();
+    Ok(if matches.is_empty() {
+        MalVal::Atom(MalAtom::Nil)
+    } else {
+        MalVal::list(matches)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::test_util::string;
+
+    fn re(s: &str) -> Regex {
+        re_pattern(string(s)).unwrap()
+    }
+
+    #[test]
+    fn test_re_find() {
+        assert_eq!(re_find(re(r"\d+"), "ab 12 34".into()), Ok(string("12")));
+        assert_eq!(
+            re_find(re(r"(\w+)=(\d+)?"), "x= y=2".into()),
+            Ok(MalVal::vector(vec![
+                string("x="),
+                string("x"),
+                MalVal::Atom(MalAtom::Nil)
+            ]))
+        );
+        assert_eq!(
+            re_find(re("z"), "abc".into()),
+            Ok(MalVal::Atom(MalAtom::Nil))
+        );
+        assert!(matches!(re_pattern(string("(")), Err(EvalError::Regex(_))));
+    }
+
+    #[test]
+    fn test_re_matches() {
+        assert_eq!(re_matches(re("a|ab"), "ab".into()), Ok(string("ab")));
+        assert_eq!(
+            re_matches(re(r"\d"), "12".into()),
+            Ok(MalVal::Atom(MalAtom::Nil))
+        );
+        assert_eq!(
+            re_matches(re("(?m)a$"), "a\nb".into()),
+            Ok(MalVal::Atom(MalAtom::Nil))
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! String builtins. Indices count characters rather than bytes, so they
+//! are stable across non-ASCII text.
+
+use std::convert::TryFrom;
+
+use crate::types::{convert::FromMal, EvalError, EvalResult, MalAtom, MalVal};
+
+/// The text of `v` as `str` would produce it: strings and characters
+/// without quotes, nil as nothing and anything else as printed.
+pub(super) fn to_str(v: &MalVal) -> String {
+    match v {
+        MalVal::Atom(MalAtom::Str(s)) => s.clone(),
+        MalVal::Atom(MalAtom::Char(c)) => c.to_string(),
+        MalVal::Atom(MalAtom::Nil) => String::new(),
+        v => v.to_string(),
+    }
+}
+
+/// A string or a character, as accepted where a pattern is matched.
+fn text(v: MalVal) -> EvalResult<String> {
+    match v {
+        MalVal::Atom(MalAtom::Str(s)) => Ok(s),
+        MalVal::Atom(MalAtom::Char(c)) => Ok(c.to_string()),
+        v => Err(EvalError::UnexpectedType("a string", v.to_string())),
+    }
+}
+
+/// Checks that `i` is a character index into a string of `len` characters,
+/// where `len` itself denotes the end.
+fn index(i: i64, len: usize) -> EvalResult<usize> {
+    match usize::try_from(i) {
+        Ok(i) if i <= len => Ok(i),
+        _ => Err(EvalError::IndexOutOfBounds(i, len)),
+    }
+}
+
+/// The byte offset of character `i` in `s`.
+fn byte_offset(s: &str, i: usize) -> usize {
+    s.char_indices().nth(i).map_or(s.len(), |(b, _)| b)
+}
+
+/// `(subs s start end?)`, the characters of `s` from `start` up to `end`,
+/// or to the end of the string.
+pub fn subs(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.len() != 2 && args.len() != 3 {
+        return Err(EvalError::InvalidArgs);
+    }
+    let mut args = args.into_iter();
+    let s = String::from_mal(args.next().unwrap())?;
+    let len = s.chars().count();
+    let start = index(i64::from_mal(args.next().unwrap())?, len)?;
+    let end = match args.next() {
+        Some(end) => index(i64::from_mal(end)?, len)?,
+        None => len,
+    };
+    if end < start {
+        return Err(EvalError::IndexOutOfBounds(end as i64, len));
+    }
+    let sub = &s[byte_offset(&s, start)..byte_offset(&s, end)];
+    Ok(MalVal::Atom(MalAtom::Str(sub.to_owned())))
+}
+
+/// `(str/split s sep)` splits `s` at each occurrence of `sep` into a vector.
+/// An empty separator splits it into its characters.
+pub fn split(s: String, sep: MalVal) -> EvalResult<MalVal> {
+    let sep = text(sep)?;
+    let parts: Vec<MalVal> = if sep.is_empty() {
+        s.chars()
+            .map(|c| MalVal::Atom(MalAtom::Str(c.to_string())))
+            .collect()
+    } else {
+        s.split(sep.as_str())
+            .map(|part| MalVal::Atom(MalAtom::Str(part.to_owned())))
+            .collect()
+    };
+    Ok(MalVal::vector(parts))
+}
+
+/// `(str/join coll)` or `(str/join sep coll)` concatenates the items of
+/// `coll` as `str` would, with `sep` between them.
+pub fn join(mut args: Vec<MalVal>) -> EvalResult<MalVal> {
+    let (sep, coll) = match args.len() {
+        1 => (String::new(), args.pop().unwrap()),
+        2 => {
+            let coll = args.pop().unwrap();
+            (text(args.pop().unwrap())?, coll)
+        }
+        _ => return Err(EvalError::InvalidArgs),
+    };
+    let items: Vec<String> = match &coll {
+        MalVal::List(seq, _) | MalVal::Vector(seq, _) => seq.iter().map(to_str).collect(),
+        MalVal::Set(set, _) => set.iter().map(to_str).collect(),
+        MalVal::Atom(MalAtom::Nil) => vec![],
+        _ => return Err(EvalError::NotAList),
+    };
+    Ok(MalVal::Atom(MalAtom::Str(items.join(&sep))))
+}
+
+pub fn trim(s: String) -> EvalResult<String> {
+    Ok(s.trim().to_owned())
+}
+
+pub fn upper_case(s: String) -> EvalResult<String> {
+    Ok(s.to_uppercase())
+}
+
+pub fn lower_case(s: String) -> EvalResult<String> {
+    Ok(s.to_lowercase())
+}
+
+/// `(str/replace s match replacement)` replaces every occurrence of `match`.
+/// If `match` is a regex, `$1` or `${name}` in the replacement stands for
+/// a capture group.
+pub fn replace(s: String, from: MalVal, to: MalVal) -> EvalResult<String> {
+    if let MalVal::Regex(re) = from {
+        return Ok(re.0.replace_all(&s, text(to)?.as_str()).into_owned());
+    }
+    let from = text(from)?;
+    if from.is_empty() {
+        return Err(EvalError::InvalidArgs);
+    }
+    Ok(s.replace(&from, &text(to)?))
+}
+
+pub fn starts_with(s: String, prefix: MalVal) -> EvalResult<bool> {
+    Ok(s.starts_with(&text(prefix)?))
+}
+
+/// `(str/index-of s needle from?)`, the character index of the first
+/// occurrence of `needle` at or after `from`, or nil.
+pub fn index_of(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    if args.len() != 2 && args.len() != 3 {
+        return Err(EvalError::InvalidArgs);
+    }
+    let mut args = args.into_iter();
+    let s = String::from_mal(args.next().unwrap())?;
+    let needle = text(args.next().unwrap())?;
+    let from = match args.next() {
+        Some(from) => index(i64::from_mal(from)?, s.chars().count())?,
+        None => 0,
+    };
+    let offset = byte_offset(&s, from);
+    Ok(match s[offset..].find(&needle) {
+        Some(b) => MalVal::Atom(MalAtom::Int(
+            (from + s[offset..offset + b].chars().count()) as i64,
+        )),
+        None => MalVal::Atom(MalAtom::Nil),
+    })
+}
+
+/// `(str/format fmt args...)` substitutes the arguments, as `str` would
+/// print them, for the placeholders in `fmt`. `{}` takes the next argument
+/// and `{n}` the argument at position `n`; `{{` and `}}` are literal braces.
+pub fn format(args: Vec<MalVal>) -> EvalResult<MalVal> {
+    let mut args = args.into_iter();
+    let fmt = String::from_mal(args.next().ok_or(EvalError::InvalidArgs)?)?;
+    let args: Vec<MalVal> = args.collect();
+    let mut out = String::new();
+    let mut next = 0;
+    let mut it = fmt.chars();
+    while let Some(c) = it.next() {
+        match c {
+            '{' => {
+                let mut spec = String::new();
+                loop {
+                    match it.next() {
+                        Some('{') if spec.is_empty() => {
+                            out.push('{');
+                            break;
+                        }
+                        Some('}') => {
+                            let i = if spec.is_empty() {
+                                next += 1;
+                                next - 1
+                            } else {
+                                spec.parse().map_err(|_| {
+                                    EvalError::Format(format!("bad placeholder {{{}}}", spec))
+                                })?
+                            };
+                            let arg = args.get(i).ok_or_else(|| {
+                                EvalError::Format(format!("no argument at position {}", i))
+                            })?;
+                            out.push_str(&to_str(arg));
+                            break;
+                        }
+                        Some(c) => spec.push(c),
+                        None => return Err(EvalError::Format("unclosed {".to_owned())),
+                    }
+                }
+            }
+            '}' => match it.next() {
+                Some('}') => out.push('}'),
+                _ => return Err(EvalError::Format("unmatched }".to_owned())),
+            },
+            c => out.push(c),
+        }
+    }
+    Ok(MalVal::Atom(MalAtom::Str(out)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::test_util::{int, string};
+
+    #[test]
+    fn test_subs_and_index_of() {
+        assert_eq!(
+            subs(vec![string("héllo"), int(1), int(3)]),
+            Ok(string("él"))
+        );
+        assert_eq!(subs(vec![string("héllo"), int(2)]), Ok(string("llo")));
+        assert_eq!(subs(vec![string("héllo"), int(5)]), Ok(string("")));
+        assert_eq!(
+            subs(vec![string("héllo"), int(6)]),
+            Err(EvalError::IndexOutOfBounds(6, 5))
+        );
+        assert_eq!(
+            subs(vec![string("héllo"), int(3), int(2)]),
+            Err(EvalError::IndexOutOfBounds(2, 5))
+        );
+
+        assert_eq!(index_of(vec![string("añoaño"), string("o")]), Ok(int(2)));
+        assert_eq!(
+            index_of(vec![string("añoaño"), string("o"), int(3)]),
+            Ok(int(5))
+        );
+        assert_eq!(
+            index_of(vec![string("año"), string("x")]),
+            Ok(MalVal::Atom(MalAtom::Nil))
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let fmt =
+            |f: &str, args: Vec<MalVal>| format(std::iter::once(string(f)).chain(args).collect());
+        assert_eq!(
+            fmt(
+                "{} + {} = {1}{{{}}}",
+                vec![int(1), string("two"), MalVal::Atom(MalAtom::Nil)]
+            ),
+            Ok(string("1 + two = two{}"))
+        );
+        assert_eq!(
+            fmt("{0}{}", vec![MalVal::vector(vec![int(1)])]),
+            Ok(string("[1][1]"))
+        );
+        assert!(matches!(
+            fmt("{1}", vec![int(1)]),
+            Err(EvalError::Format(_))
+        ));
+        assert!(matches!(fmt("{x}", vec![]), Err(EvalError::Format(_))));
+        assert!(matches!(fmt("{", vec![]), Err(EvalError::Format(_))));
+        assert!(matches!(fmt("}", vec![]), Err(EvalError::Format(_))));
+    }
+}
@@ -0,0 +1,396 @@
+use std::rc::Rc;
+
+use super::analyze::{Expr, Lambda};
+use crate::types::{symbol::Symbol, MalAtom, MalVal, Meta, Seq};
+
+/// A VM instruction. Operands index into the tables of the enclosing
+/// `Chunk`; jump targets are instruction offsets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Const(u32),
+    LoadLocal(u32, u32),
+    LoadGlobal(Symbol),
+    /// Names the function on top of the stack, if unnamed, and stores it
+    /// in a slot of the current frame, leaving it on the stack.
+    DefLocal(Symbol, u32),
+    /// Like `DefLocal`, but stores the value by name in the current
+    /// environment.
+    DefGlobal(Symbol),
+    /// Collects the top `n` values into a vector with the given metadata.
+    Vector(u32, u32),
+    /// Collects the top `2 * n` values into a map with the given metadata.
+    Map(u32, u32),
+    /// Collects the top `n` values into a set with the given metadata.
+    Set(u32, u32),
+    Closure(u32),
+    Pop,
+    Jump(u32),
+    JumpIfFalse(u32),
+    /// Enters a new frame of local variables with the given slot names.
+    PushFrame(u32),
+    /// Pops a value into a slot of the current frame.
+    StoreLocal(u32),
+    /// Leaves the given number of frames.
+    PopFrame(u32),
+    /// Calls the function below the top `argc` values. The last operand
+    /// indexes the call site, which is used for stack traces.
+    Call(u32, u32),
+    TailCall(u32, u32),
+    Return,
+    /// Starts the next iteration of a loop: leaves `up` frames opened inside
+    /// the loop body plus the loop's own frame, opens a fresh frame with
+    /// the given names holding the top `argc` values and jumps to `target`.
+    Recur {
+        argc: u32,
+        up: u32,
+        names: u32,
+        target: u32,
+    },
+    RecurOutsideLoop,
+    Special(u32),
+}
+
+/// Compiled code for a top-level form or a function body.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub consts: Vec<MalVal>,
+    pub metas: Vec<Meta>,
+    pub names: Vec<Rc<[Symbol]>>,
+    pub protos: Vec<Proto>,
+    /// Head symbol and source of each call, for stack traces.
+    pub sites: Vec<(Option<Symbol>, MalVal)>,
+    /// Name and source of each embedder special form.
+    pub specials: Vec<(Symbol, Seq)>,
+}
+
+/// A `fn*` along with the compiled code of its body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proto {
+    pub lambda: Rc<Lambda>,
+    pub chunk: Rc<Chunk>,
+}
+
+/// Compiles an analyzed form. The resulting chunk returns the value of the
+/// form.
+pub fn compile(expr: &Expr) -> Chunk {
+    let mut compiler = Compiler::default();
+    compiler.expr(expr, Tail::Return);
+    compiler.chunk
+}
+
+/// What happens to the value of an expression once it is computed.
+#[derive(Clone, Copy, PartialEq)]
+enum Tail {
+    /// It is left on the stack for the enclosing expression.
+    Push,
+    /// It is returned from the chunk.
+    Return,
+    /// It ends the innermost loop.
+    Loop,
+}
+
+struct LoopInfo {
+    start: u32,
+    /// Number of frames open at the start of the loop body, including the
+    /// loop's own.
+    frames: u32,
+    names: u32,
+    /// Jumps to the end of the loop, patched once it is known.
+    exits: Vec<usize>,
+}
+
+#[derive(Default)]
+struct Compiler {
+    chunk: Chunk,
+    /// Number of frames opened by `PushFrame` that are currently open.
+    frames: u32,
+    loops: Vec<LoopInfo>,
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.code.len() - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.chunk.code.len() as u32
+    }
+
+    fn patch(&mut self, at: usize, target: u32) {
+        match &mut self.chunk.code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) => *t = target,
+            op => unreachable!("cannot patch {:?}", op),
+        }
+    }
+
+    fn add<T>(table: &mut Vec<T>, value: T) -> u32 {
+        table.push(value);
+        (table.len() - 1) as u32
+    }
+
+    /// Finishes a value-producing expression in the given tail position.
+    fn finish(&mut self, tail: Tail) {
+        match tail {
+            Tail::Push => {}
+            Tail::Return => {
+                self.emit(Op::Return);
+            }
+            Tail::Loop => {
+                let info = self.loops.last().unwrap();
+                let up = self.frames - info.frames;
+                if up > 0 {
+                    self.emit(Op::PopFrame(up));
+                }
+                let at = self.emit(Op::Jump(0));
+                self.loops.last_mut().unwrap().exits.push(at);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, tail: Tail) {
+        match expr {
+            Expr::Const(v) => {
+                let i = Self::add(&mut self.chunk.consts, v.clone());
+                self.emit(Op::Const(i));
+                self.finish(tail);
+            }
+            Expr::Local { depth, slot } => {
+                self.emit(Op::LoadLocal(*depth as u32, *slot as u32));
+                self.finish(tail);
+            }
+            Expr::Global(sym) => {
+                self.emit(Op::LoadGlobal(*sym));
+                self.finish(tail);
+            }
+            Expr::Vector(items, meta) => {
+                for item in items {
+                    self.expr(item, Tail::Push);
+                }
+                let m = Self::add(&mut self.chunk.metas, meta.clone());
+                self.emit(Op::Vector(items.len() as u32, m));
+                self.finish(tail);
+            }
+            Expr::Map(entries, meta) => {
+                for (k, v) in entries {
+                    self.expr(k, Tail::Push);
+                    self.expr(v, Tail::Push);
+                }
+                let m = Self::add(&mut self.chunk.metas, meta.clone());
+                self.emit(Op::Map(entries.len() as u32, m));
+                self.finish(tail);
+            }
+            Expr::Set(items, meta) => {
+                for item in items {
+                    self.expr(item, Tail::Push);
+                }
+                let m = Self::add(&mut self.chunk.metas, meta.clone());
+                self.emit(Op::Set(items.len() as u32, m));
+                self.finish(tail);
+            }
+            Expr::Def { sym, slot, value } => {
+                self.expr(value, Tail::Push);
+                match slot {
+                    Some(slot) => self.emit(Op::DefLocal(*sym, *slot as u32)),
+                    None => self.emit(Op::DefGlobal(*sym)),
+                };
+                self.finish(tail);
+            }
+            Expr::Let { names, inits, body } => {
+                self.push_frame(names, inits);
+                self.expr(body, tail);
+                self.pop_frame(tail);
+            }
+            Expr::Fn(lambda) => {
+                let mut compiler = Compiler::default();
+                compiler.expr(&lambda.code, Tail::Return);
+                let proto = Proto {
+                    lambda: lambda.clone(),
+                    chunk: Rc::new(compiler.chunk),
+                };
+                let i = Self::add(&mut self.chunk.protos, proto);
+                self.emit(Op::Closure(i));
+                self.finish(tail);
+            }
+            Expr::Do(forms) => match forms.split_last() {
+                Some((last, init)) => {
+                    for form in init {
+                        self.expr(form, Tail::Push);
+                        self.emit(Op::Pop);
+                    }
+                    self.expr(last, tail);
+                }
+                None => self.expr(&Expr::Const(MalVal::Atom(MalAtom::Nil)), tail),
+            },
+            Expr::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.expr(cond, Tail::Push);
+                let to_else = self.emit(Op::JumpIfFalse(0));
+                self.expr(then, tail);
+                let to_end = (tail == Tail::Push).then(|| self.emit(Op::Jump(0)));
+                let here = self.here();
+                self.patch(to_else, here);
+                match otherwise {
+                    Some(otherwise) => self.expr(otherwise, tail),
+                    None => self.expr(&Expr::Const(MalVal::Atom(MalAtom::Nil)), tail),
+                }
+                if let Some(to_end) = to_end {
+                    let here = self.here();
+                    self.patch(to_end, here);
+                }
+            }
+            Expr::Loop { names, inits, body } => {
+                let names_index = self.push_frame(names, inits);
+                self.loops.push(LoopInfo {
+                    start: self.here(),
+                    frames: self.frames,
+                    names: names_index,
+                    exits: Vec::new(),
+                });
+                // A loop returning from the chunk can return straight from
+                // its body.
+                let body_tail = if tail == Tail::Return {
+                    Tail::Return
+                } else {
+                    Tail::Loop
+                };
+                self.expr(body, body_tail);
+                let info = self.loops.pop().unwrap();
+                self.frames -= 1;
+                if tail != Tail::Return {
+                    let here = self.here();
+                    for exit in info.exits {
+                        self.patch(exit, here);
+                    }
+                    self.emit(Op::PopFrame(1));
+                    self.finish(tail);
+                }
+            }
+            Expr::Recur(args) => match self.loops.last() {
+                // check_recur only lets recur appear in tail position of a
+                // loop, so the loop's frames are the only ones to leave.
+                Some(info) if tail != Tail::Push => {
+                    let (frames, names, start) = (info.frames, info.names, info.start);
+                    for arg in args {
+                        self.expr(arg, Tail::Push);
+                    }
+                    self.emit(Op::Recur {
+                        argc: args.len() as u32,
+                        up: self.frames - frames,
+                        names,
+                        target: start,
+                    });
+                }
+                _ => {
+                    self.emit(Op::RecurOutsideLoop);
+                }
+            },
+            Expr::Call { form, head, items } => {
+                for item in items {
+                    self.expr(item, Tail::Push);
+                }
+                let argc = items.len() as u32 - 1;
+                let site = Self::add(&mut self.chunk.sites, (*head, form.clone()));
+                if tail == Tail::Return {
+                    self.emit(Op::TailCall(argc, site));
+                } else {
+                    self.emit(Op::Call(argc, site));
+                    self.finish(tail);
+                }
+            }
+            Expr::Special(sym, list) => {
+                let i = Self::add(&mut self.chunk.specials, (*sym, list.clone()));
+                self.emit(Op::Special(i));
+                self.finish(tail);
+            }
+        }
+    }
+
+    /// Opens the frame of a `let*` or `loop` and evaluates its bindings.
+    fn push_frame(&mut self, names: &Rc<[Symbol]>, inits: &[Expr]) -> u32 {
+        let i = Self::add(&mut self.chunk.names, names.clone());
+        self.emit(Op::PushFrame(i));
+        self.frames += 1;
+        for (slot, init) in inits.iter().enumerate() {
+            self.expr(init, Tail::Push);
+            self.emit(Op::StoreLocal(slot as u32));
+        }
+        i
+    }
+
+    /// Closes the frame of a `let*` once its body is compiled. Bodies in
+    /// tail position have already left it.
+    fn pop_frame(&mut self, tail: Tail) {
+        self.frames -= 1;
+        if tail == Tail::Push {
+            self.emit(Op::PopFrame(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval::analyze::analyze, types::env::EnvironmentBuilder};
+
+    fn compile_str(input: &str) -> Chunk {
+        let env = EnvironmentBuilder::new().build();
+        let ast = crate::reader::read_str(input).unwrap().remove(0);
+        compile(&analyze(&ast, &env).unwrap())
+    }
+
+    #[test]
+    fn test_compile() {
+        let chunk = compile_str("(+ 1 2)");
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::LoadGlobal("+".into()),
+                Op::Const(0),
+                Op::Const(1),
+                Op::TailCall(2, 0),
+            ]
+        );
+
+        let chunk = compile_str("(if a 1)");
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::LoadGlobal("a".into()),
+                Op::JumpIfFalse(4),
+                Op::Const(0),
+                Op::Return,
+                Op::Const(1),
+                Op::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_loop() {
+        let chunk = compile_str("(loop [i 0] (if i (recur 1) i))");
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::PushFrame(0),
+                Op::Const(0),
+                Op::StoreLocal(0),
+                Op::LoadLocal(0, 0),
+                Op::JumpIfFalse(7),
+                Op::Const(1),
+                Op::Recur {
+                    argc: 1,
+                    up: 0,
+                    names: 0,
+                    target: 3
+                },
+                Op::LoadLocal(0, 0),
+                Op::Return,
+            ]
+        );
+    }
+}
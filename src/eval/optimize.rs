@@ -0,0 +1,209 @@
+use std::rc::Rc;
+
+use super::analyze::{Expr, Lambda};
+use crate::types::{
+    env::{EnvVal, Environment},
+    literal_map, MalAtom, MalVal,
+};
+
+/// Simplifies an analyzed form for evaluation in `env`. Calls to pure
+/// builtins whose arguments are all constant are replaced by their result,
+/// and `if` forms with a constant condition by the branch that would be
+/// taken. Calls that fail are left alone so that the error is raised when
+/// the form is evaluated, as it would be without optimization.
+pub fn optimize(expr: Expr, env: &Environment) -> Expr {
+    match expr {
+        Expr::Vector(items, meta) => {
+            let items = optimize_all(items, env);
+            match constants(&items) {
+                Some(values) => Expr::Const(MalVal::Vector(values.into_iter().collect(), meta)),
+                None => Expr::Vector(items, meta),
+            }
+        }
+        Expr::Map(entries, meta) => {
+            let entries: Vec<_> = entries
+                .into_iter()
+                .map(|(k, v)| (optimize(k, env), optimize(v, env)))
+                .collect();
+            if !entries.iter().all(|(k, v)| is_const(k) && is_const(v)) {
+                return Expr::Map(entries, meta);
+            }
+            let consts = entries
+                .iter()
+                .map(|(k, v)| (into_const(k.clone()), into_const(v.clone())));
+            // A repeated key is left for evaluation to report.
+            match literal_map(consts) {
+                Ok(map) => Expr::Const(MalVal::AssocArray(map, meta)),
+                Err(_) => Expr::Map(entries, meta),
+            }
+        }
+        Expr::Set(items, meta) => {
+            let items = optimize_all(items, env);
+            match constants(&items) {
+                Some(values) => Expr::Const(MalVal::Set(values.into_iter().collect(), meta)),
+                None => Expr::Set(items, meta),
+            }
+        }
+        Expr::Def { sym, slot, value } => Expr::Def {
+            sym,
+            slot,
+            value: Box::new(optimize(*value, env)),
+        },
+        Expr::Let { names, inits, body } => Expr::Let {
+            names,
+            inits: optimize_all(inits, env),
+            body: Box::new(optimize(*body, env)),
+        },
+        Expr::Fn(lambda) => Expr::Fn(Rc::new(Lambda {
+            code: Rc::new(optimize((*lambda.code).clone(), env)),
+            ..(*lambda).clone()
+        })),
+        Expr::Do(forms) => {
+            let mut forms = optimize_all(forms, env);
+            let last = forms.pop();
+            // Values computed only to be discarded can be skipped.
+            forms.retain(|form| !matches!(form, Expr::Const(_) | Expr::Local { .. } | Expr::Fn(_)));
+            match last {
+                Some(last) if forms.is_empty() => last,
+                Some(last) => {
+                    forms.push(last);
+                    Expr::Do(forms)
+                }
+                None => Expr::Const(MalVal::Atom(MalAtom::Nil)),
+            }
+        }
+        Expr::If {
+            cond,
+            then,
+            otherwise,
+        } => match optimize(*cond, env) {
+            Expr::Const(v) if v.is_truthy() => optimize(*then, env),
+            Expr::Const(_) => match otherwise {
+                Some(otherwise) => optimize(*otherwise, env),
+                None => Expr::Const(MalVal::Atom(MalAtom::Nil)),
+            },
+            cond => Expr::If {
+                cond: Box::new(cond),
+                then: Box::new(optimize(*then, env)),
+                otherwise: otherwise.map(|otherwise| Box::new(optimize(*otherwise, env))),
+            },
+        },
+        Expr::Loop { names, inits, body } => Expr::Loop {
+            names,
+            inits: optimize_all(inits, env),
+            body: Box::new(optimize(*body, env)),
+        },
+        Expr::Recur(args) => Expr::Recur(optimize_all(args, env)),
+        Expr::Call { form, head, items } => {
+            let items = optimize_all(items, env);
+            fold_call(&items, env).unwrap_or(Expr::Call { form, head, items })
+        }
+        expr @ (Expr::Const(_) | Expr::Local { .. } | Expr::Global(_) | Expr::Special(..)) => expr,
+    }
+}
+
+fn optimize_all(exprs: Vec<Expr>, env: &Environment) -> Vec<Expr> {
+    exprs.into_iter().map(|expr| optimize(expr, env)).collect()
+}
+
+/// Calls a pure builtin on constant arguments ahead of time.
+fn fold_call(items: &[Expr], env: &Environment) -> Option<Expr> {
+    let sym = match items.first() {
+        Some(Expr::Global(sym)) if env.is_pure_builtin(*sym) => *sym,
+        _ => return None,
+    };
+    let f = match env.get_global(sym) {
+        Some(EnvVal::NativeFn(f)) => f,
+        _ => return None,
+    };
+    let args = constants(&items[1..])?;
+    f(args).ok().map(Expr::Const)
+}
+
+fn is_const(expr: &Expr) -> bool {
+    matches!(expr, Expr::Const(_))
+}
+
+fn into_const(expr: Expr) -> MalVal {
+    match expr {
+        Expr::Const(v) => v,
+        _ => unreachable!("not a constant: {:?}", expr),
+    }
+}
+
+/// The values of `exprs` if they are all constant.
+fn constants(exprs: &[Expr]) -> Option<Vec<MalVal>> {
+    exprs
+        .iter()
+        .map(|expr| match expr {
+            Expr::Const(v) => Some(v.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eval::{analyze::analyze, builtin},
+        types::env::EnvironmentBuilder,
+    };
+
+    fn optimize_str(input: &str) -> Expr {
+        let env = EnvironmentBuilder::new()
+            .with_builtins(builtin::defaults())
+            .with_pure_builtins(builtin::pure())
+            .build();
+        let ast = crate::reader::read_str(input).unwrap().remove(0);
+        optimize(analyze(&ast, &env).unwrap(), &env)
+    }
+
+    fn int(i: i64) -> Expr {
+        Expr::Const(MalVal::Atom(MalAtom::Int(i)))
+    }
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(optimize_str("(+ 1 2)"), int(3));
+        assert_eq!(optimize_str("(* (+ 1 2) (- 5 1))"), int(12));
+        assert_eq!(optimize_str("(count [1 (+ 1 1) 3])"), int(3));
+
+        // Calls that fail or depend on variables are kept.
+        assert!(matches!(optimize_str("(+ 1 nil)"), Expr::Call { .. }));
+        match optimize_str("(fn* (a) (+ a (+ 1 2)))") {
+            Expr::Fn(lambda) => match &*lambda.code {
+                Expr::Call { items, .. } => assert_eq!(items[2], int(3)),
+                expr => panic!("expected a call, got {:?}", expr),
+            },
+            expr => panic!("expected a lambda, got {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_impure_builtins() {
+        let env = EnvironmentBuilder::new()
+            .with_builtins(builtin::defaults())
+            .build();
+        let ast = crate::reader::read_str("(+ 1 2)").unwrap().remove(0);
+        let expr = optimize(analyze(&ast, &env).unwrap(), &env);
+        assert!(matches!(expr, Expr::Call { .. }));
+    }
+
+    #[test]
+    fn test_prune() {
+        assert_eq!(optimize_str("(if true 1 2)"), int(1));
+        assert_eq!(optimize_str("(if (= 1 2) 1 2)"), int(2));
+        assert_eq!(
+            optimize_str("(if nil 1)"),
+            Expr::Const(MalVal::Atom(MalAtom::Nil))
+        );
+        assert!(matches!(optimize_str("(if x 1 2)"), Expr::If { .. }));
+
+        assert_eq!(optimize_str("(do 1 2 (+ 1 2))"), int(3));
+        assert_eq!(
+            optimize_str("(do 1 x 2)"),
+            Expr::Do(vec![Expr::Global("x".into()), int(2)])
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::{collections::VecDeque, rc::Rc};
+
+use itertools::Itertools;
+
+use super::{
+    apply_native_fn, bind_args, closure,
+    compile::{compile, Chunk, Op},
+    custom_form, exec_global, name_fn,
+};
+use crate::types::{
+    env::{Budget, DepthGuard, Environment, EnvironmentBuilder},
+    literal_map, EvalError, EvalResult, Frame, MalAtom, MalFn, MalVal, MAX_TRACE_FRAMES,
+};
+
+/// Runs a compiled top-level form in `env`.
+///
+/// Every instruction consumes one step of the budget and every call to a
+/// MAL function one level of depth. Tail calls reuse the caller's frame,
+/// so they count towards neither the depth nor the native stack.
+pub fn run(chunk: Rc<Chunk>, env: &Environment) -> EvalResult<MalVal> {
+    let budget = env.budget();
+    let depth = budget.descend()?;
+    let mut vm = Vm {
+        budget,
+        stack: Vec::new(),
+        frames: vec![CallFrame {
+            chunk,
+            ip: 0,
+            env: env.clone(),
+            base: 0,
+            site: None,
+            elided: VecDeque::new(),
+            omitted: 0,
+            _depth: depth,
+        }],
+    };
+    vm.run().map_err(|e| vm.trace(e))
+}
+
+/// The bytecode of the body of `f`.
+pub fn chunk(f: &MalFn) -> Rc<Chunk> {
+    match &f.chunk {
+        Some(chunk) => chunk.clone(),
+        // Functions created by the tree-walking evaluator have no bytecode
+        // yet.
+        None => Rc::new(compile(&f.code)),
+    }
+}
+
+struct Vm {
+    budget: Budget,
+    stack: Vec<MalVal>,
+    frames: Vec<CallFrame>,
+}
+
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    /// The innermost environment, which changes as `let*` and `loop`
+    /// frames are entered and left.
+    env: Environment,
+    /// Stack height when the frame was entered.
+    base: usize,
+    /// The call that entered this frame, or `None` for the top level.
+    site: Option<CallSite>,
+    /// Calls this frame was reused for by tail calls, most recent first,
+    /// so that stack traces list them like any other call. Only the most
+    /// recent ones are kept; the rest are counted in `omitted`.
+    elided: VecDeque<CallSite>,
+    omitted: usize,
+    _depth: DepthGuard,
+}
+
+struct CallSite {
+    callee: Rc<MalFn>,
+    chunk: Rc<Chunk>,
+    index: u32,
+}
+
+impl CallSite {
+    fn frame(&self) -> Frame {
+        let (head, form) = &self.chunk.sites[self.index as usize];
+        let name = self
+            .callee
+            .name
+            .clone()
+            .or_else(|| head.map(|h| h.to_string()));
+        Frame::new(name, form.clone())
+    }
+}
+
+impl Vm {
+    fn run(&mut self) -> EvalResult<MalVal> {
+        loop {
+            self.budget.step()?;
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame.chunk.code[frame.ip];
+            frame.ip += 1;
+            match op {
+                Op::Const(i) => self.stack.push(frame.chunk.consts[i as usize].clone()),
+                Op::LoadLocal(depth, slot) => {
+                    let v = match frame.env.get_local(depth as usize, slot as usize) {
+                        Ok(v) => v,
+                        Err(sym) => exec_global(sym, &frame.env)?,
+                    };
+                    self.stack.push(v);
+                }
+                Op::LoadGlobal(sym) => self.stack.push(exec_global(sym, &frame.env)?),
+                Op::DefLocal(sym, slot) => {
+                    let value = self.stack.last_mut().unwrap();
+                    name_fn(value, sym);
+                    frame.env.set_local(slot as usize, value.clone());
+                }
+                Op::DefGlobal(sym) => {
+                    let value = self.stack.last_mut().unwrap();
+                    name_fn(value, sym);
+                    frame.env.set(sym, value.clone());
+                }
+                Op::Vector(n, meta) => {
+                    let items = self.stack.split_off(self.stack.len() - n as usize);
+                    let meta = frame.chunk.metas[meta as usize].clone();
+                    self.stack
+                        .push(MalVal::Vector(items.into_iter().collect(), meta));
+                }
+                Op::Map(n, meta) => {
+                    let items = self.stack.split_off(self.stack.len() - 2 * n as usize);
+                    let meta = frame.chunk.metas[meta as usize].clone();
+                    let map = literal_map(items.into_iter().tuples())
+                        .map_err(|k| EvalError::DuplicateKey(k.to_string()))?;
+                    self.stack.push(MalVal::AssocArray(map, meta));
+                }
+                Op::Set(n, meta) => {
+                    let items = self.stack.split_off(self.stack.len() - n as usize);
+                    let meta = frame.chunk.metas[meta as usize].clone();
+                    self.stack
+                        .push(MalVal::Set(items.into_iter().collect(), meta));
+                }
+                Op::Closure(i) => {
+                    let proto = &frame.chunk.protos[i as usize];
+                    let f = closure(&proto.lambda, &frame.env, Some(proto.chunk.clone()));
+                    self.stack.push(f);
+                }
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::Jump(target) => frame.ip = target as usize,
+                Op::JumpIfFalse(target) => {
+                    if !self.stack.pop().unwrap().is_truthy() {
+                        frame.ip = target as usize;
+                    }
+                }
+                Op::PushFrame(names) => {
+                    frame.env = EnvironmentBuilder::new()
+                        .with_parent(&frame.env)
+                        .with_frame(frame.chunk.names[names as usize].clone(), Vec::new())
+                        .build();
+                }
+                Op::StoreLocal(slot) => {
+                    let value = self.stack.pop().unwrap();
+                    frame.env.set_local(slot as usize, value);
+                }
+                Op::PopFrame(n) => {
+                    for _ in 0..n {
+                        frame.env = frame.env.parent().unwrap();
+                    }
+                }
+                Op::Call(argc, site) => {
+                    self.call(argc, site, false)?;
+                }
+                Op::TailCall(argc, site) => {
+                    if let Some(v) = self.call(argc, site, true)? {
+                        return Ok(v);
+                    }
+                }
+                Op::Return => {
+                    let v = self.stack.pop().unwrap();
+                    if let Some(v) = self.ret(v) {
+                        return Ok(v);
+                    }
+                }
+                Op::Recur {
+                    argc,
+                    up,
+                    names,
+                    target,
+                } => {
+                    let args = self.stack.split_off(self.stack.len() - argc as usize);
+                    let mut outer = frame.env.clone();
+                    for _ in 0..=up {
+                        outer = outer.parent().unwrap();
+                    }
+                    frame.env = EnvironmentBuilder::new()
+                        .with_parent(&outer)
+                        .with_frame(frame.chunk.names[names as usize].clone(), args)
+                        .build();
+                    frame.ip = target as usize;
+                }
+                Op::RecurOutsideLoop => return Err(EvalError::RecurOutsideLoop),
+                Op::Special(i) => {
+                    let (sym, list) = &frame.chunk.specials[i as usize];
+                    let v = custom_form(*sym, &frame.env)(&frame.env, list)?;
+                    self.stack.push(v);
+                }
+            }
+        }
+    }
+
+    /// Calls the function below the top `argc` values of the stack. A tail
+    /// call that completes without entering a new frame returns the value
+    /// of the whole evaluation once the last frame is left.
+    fn call(&mut self, argc: u32, site: u32, tail: bool) -> EvalResult<Option<MalVal>> {
+        let args = self.stack.split_off(self.stack.len() - argc as usize);
+        let frame = self.frames.last_mut().unwrap();
+        let f = match self.stack.pop().unwrap() {
+            MalVal::Atom(MalAtom::Sym(sym)) => {
+                let v = apply_native_fn(sym, args, &frame.env)?;
+                if tail {
+                    return Ok(self.ret(v));
+                }
+                self.stack.push(v);
+                return Ok(None);
+            }
+            MalVal::Fn(f) => f,
+            f => return Err(EvalError::BadFunctionDesignator(f.to_string())),
+        };
+        let env = bind_args(&f, args)?;
+        let chunk = chunk(&f);
+        let site = CallSite {
+            callee: f,
+            chunk: frame.chunk.clone(),
+            index: site,
+        };
+
+        if tail {
+            self.stack.truncate(frame.base);
+            if let Some(replaced) = frame.site.replace(site) {
+                frame.elided.push_front(replaced);
+                if frame.elided.len() > MAX_TRACE_FRAMES {
+                    frame.elided.pop_back();
+                    frame.omitted += 1;
+                }
+            }
+            frame.chunk = chunk;
+            frame.ip = 0;
+            frame.env = env;
+        } else {
+            let depth = self
+                .budget
+                .descend()
+                .map_err(|e| e.with_frame(site.frame()))?;
+            self.frames.push(CallFrame {
+                chunk,
+                ip: 0,
+                env,
+                base: self.stack.len(),
+                site: Some(site),
+                elided: VecDeque::new(),
+                omitted: 0,
+                _depth: depth,
+            });
+        }
+        Ok(None)
+    }
+
+    /// Leaves the current frame with `v`, which is returned once the
+    /// outermost frame is left.
+    fn ret(&mut self, v: MalVal) -> Option<MalVal> {
+        let frame = self.frames.pop().unwrap();
+        if self.frames.is_empty() {
+            return Some(v);
+        }
+        self.stack.truncate(frame.base);
+        self.stack.push(v);
+        None
+    }
+
+    /// Adds the calls that are still in progress to the trace of `err`.
+    fn trace(&self, mut err: EvalError) -> EvalError {
+        for frame in self.frames.iter().rev() {
+            for site in frame.site.iter().chain(&frame.elided) {
+                err = err.with_frame(site.frame());
+            }
+            err = err.with_omitted_frames(frame.omitted);
+        }
+        err
+    }
+}
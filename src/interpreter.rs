@@ -0,0 +1,262 @@
+use thiserror::Error;
+
+use crate::{
+    eval::{self, builtin},
+    reader::{self, ParseError},
+    types::{
+        convert::IntoNativeFn,
+        env::{Environment, EnvironmentBuilder},
+        EvalError, MalAtom, MalVal,
+    },
+};
+
+/// An embedded interpreter: a root environment holding the default
+/// builtins along with everything defined in it so far.
+pub struct Interpreter {
+    env: Environment,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Eval(#[from] EvalError),
+}
+
+impl Interpreter {
+    /// Creates an interpreter with the default builtins and limits.
+    pub fn new() -> Self {
+        Self::with_builder(EnvironmentBuilder::new())
+    }
+
+    /// Creates an interpreter whose root environment is configured by
+    /// `builder`. The default builtins are added to whatever it registers,
+    /// except where it already registered a builtin of the same name.
+    pub fn with_builder(builder: EnvironmentBuilder) -> Self {
+        let env = builder
+            .with_default_builtins(builtin::defaults(), builtin::pure())
+            .build();
+        Interpreter { env }
+    }
+
+    /// The root environment, for use with the lower-level `eval` API.
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+
+    /// Evaluates every form in `input` and returns the value of the last
+    /// one, or nil if there is none. All forms share one evaluation
+    /// budget.
+    pub fn eval_str(&self, input: &str) -> Result<MalVal, Error> {
+        let forms = reader::read_str(input)?;
+        self.env.reset_budget();
+        let mut result = Ok(MalVal::Atom(MalAtom::Nil));
+        for form in &forms {
+            result = eval::eval(form, &self.env);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.env.collect_cycles();
+        Ok(result?)
+    }
+
+    /// Defines `name` as `value` in the root environment, like `def!`.
+    pub fn define(&self, name: &str, value: MalVal) {
+        self.env.set(name.into(), value);
+    }
+
+    /// Defines `name` as a builtin implemented by `f`, e.g. a closure
+    /// `|a: i64, b: String| -> EvalResult<bool> { .. }`. See
+    /// `IntoNativeFn` for the functions that can be used.
+    pub fn define_fn<Args, F: IntoNativeFn<Args>>(&self, name: &str, f: F) {
+        self.env.set_builtin(name.into(), f.into_native_fn());
+    }
+
+    /// Calls the function defined as `name` with `args`, which are passed
+    /// as they are rather than evaluated.
+    pub fn call(&self, name: &str, args: Vec<MalVal>) -> Result<MalVal, Error> {
+        self.env.reset_budget();
+        let f = eval::eval(&MalVal::Atom(MalAtom::Sym(name.into())), &self.env)?;
+        let result = eval::apply(&f, args, &self.env);
+        self.env.collect_cycles();
+        Ok(result?)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{env::Backend, test_util::int, EvalResult};
+    use std::{cell::Cell, rc::Rc};
+
+    #[test]
+    fn test_eval_str() {
+        let interp = Interpreter::new();
+        let evaluated = interp.eval_str("(def! a 2) (* a 3)").unwrap();
+        assert_eq!(evaluated, int(6));
+        assert_eq!(interp.eval_str("").unwrap(), MalVal::Atom(MalAtom::Nil));
+
+        assert!(matches!(interp.eval_str("(+ 1"), Err(Error::Parse(_))));
+        assert!(matches!(
+            interp.eval_str("(+ 1 nil) (def! b 1)"),
+            Err(Error::Eval(EvalError::NotANumber))
+        ));
+        // Evaluation stops at the first error.
+        assert!(interp.eval_str("b").is_err());
+    }
+
+    #[test]
+    fn test_define_and_call() {
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let interp = Interpreter::with_builder(EnvironmentBuilder::new().with_backend(backend));
+            interp.define("x", int(4));
+            interp.eval_str("(def! add (fn* (a b) (+ a b x)))").unwrap();
+            assert_eq!(interp.call("add", vec![int(1), int(2)]).unwrap(), int(7));
+            assert_eq!(interp.call("*", vec![int(2), int(3)]).unwrap(), int(6));
+
+            // Arguments are not evaluated.
+            let list = interp.eval_str("(list 1 2)").unwrap();
+            assert_eq!(interp.call("count", vec![list]).unwrap(), int(2));
+
+            let err = interp.call("add", vec![int(1), MalVal::Atom(MalAtom::Nil)]);
+            assert_eq!(
+                err.unwrap_err().to_string(),
+                "Not a number\n  at add: (add 1 nil)"
+            );
+            assert!(matches!(
+                interp.call("missing", vec![]),
+                Err(Error::Eval(EvalError::SymbolNotFound(_)))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_default_depth_fits_thread_stack() {
+        // Deep recursion fails cleanly rather than overflowing the native
+        // stack of a thread with Rust's default size.
+        let result = std::thread::Builder::new()
+            .stack_size(2 * 1024 * 1024)
+            .spawn(|| {
+                let interp = Interpreter::new();
+                interp
+                    .eval_str(
+                        "(def! f (fn* (n) (if (= n 0) 0 (+ 1 (let* (m (- n 1)) (do (f m)))))))",
+                    )
+                    .unwrap();
+                interp.eval_str("(f 100000)").unwrap_err().to_string()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(result.starts_with("Stack overflow"), "{}", result);
+    }
+
+    #[test]
+    fn test_define_fn() {
+        let interp = Interpreter::new();
+        interp.define_fn("repeat", |s: String, n: i64| -> EvalResult<String> {
+            Ok(s.repeat(n as usize))
+        });
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        interp.define_fn("tick", move || -> EvalResult<i64> {
+            counter.set(counter.get() + 1);
+            Ok(counter.get())
+        });
+
+        let evaluated = interp.eval_str("(repeat \"ab\" 2)").unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Str("abab".to_owned())));
+        interp.eval_str("(tick) (tick)").unwrap();
+        assert_eq!(calls.get(), 2);
+
+        assert!(matches!(
+            interp.eval_str("(repeat 1 2)"),
+            Err(Error::Eval(EvalError::UnexpectedType("a string", _)))
+        ));
+        assert!(matches!(
+            interp.eval_str("(repeat \"ab\")"),
+            Err(Error::Eval(EvalError::InvalidArgs))
+        ));
+    }
+
+    #[test]
+    fn test_builder_builtins_take_precedence() {
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let interp =
+            Interpreter::with_builder(EnvironmentBuilder::new().with_optimize(true).with_fn(
+                "count",
+                move |_: MalVal| -> EvalResult<i64> {
+                    counter.set(counter.get() + 1);
+                    Ok(-1)
+                },
+            ));
+        let evaluated = interp.eval_str("(def! f (fn* () (count [1 2]))) (f) (f)");
+        assert_eq!(evaluated.unwrap(), int(-1));
+        // Not folded ahead of time, although the default count is pure.
+        assert_eq!(calls.get(), 2);
+        // The other defaults are still there.
+        assert_eq!(interp.eval_str("(+ 1 2)").unwrap(), int(3));
+    }
+
+    #[test]
+    fn test_io() {
+        // Without the capability the effectful builtins do not exist.
+        let interp = Interpreter::new();
+        assert!(matches!(
+            interp.eval_str("(getenv \"PATH\")"),
+            Err(Error::Eval(EvalError::SymbolNotFound(_)))
+        ));
+
+        let interp = Interpreter::with_builder(EnvironmentBuilder::new().with_io(true));
+        let path = std::env::temp_dir().join(format!("mal-interp-io-{}.txt", std::process::id()));
+        interp.define(
+            "path",
+            MalVal::Atom(MalAtom::Str(path.to_str().unwrap().to_owned())),
+        );
+        let evaluated = interp
+            .eval_str("(spit path [1 2]) (def! s (slurp path)) (delete-file path) s")
+            .unwrap();
+        assert_eq!(evaluated, MalVal::Atom(MalAtom::Str("[1 2]".to_owned())));
+        assert_eq!(
+            interp.eval_str("(file-exists? path)").unwrap(),
+            MalVal::Atom(MalAtom::False)
+        );
+        assert!(matches!(
+            interp.eval_str("(slurp path)"),
+            Err(Error::Eval(EvalError::Io(_)))
+        ));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        let interp = Interpreter::new();
+        let evaluated = interp
+            .eval_str(r#"(get (json-parse "{\"a\": {\"b\": 3}}" true) :a)"#)
+            .unwrap();
+        assert_eq!(evaluated.to_string(), "{:b 3}");
+        let evaluated = interp
+            .eval_str(r#"(json-stringify (json-parse "[1,{\"k\":null}]"))"#)
+            .unwrap();
+        assert_eq!(
+            evaluated,
+            MalVal::Atom(MalAtom::Str(r#"[1,{"k":null}]"#.to_owned()))
+        );
+        assert_eq!(
+            interp
+                .eval_str("(json-stringify [(fn* (x) x)])")
+                .unwrap_err()
+                .to_string(),
+            "Conversion failed: cannot serialize function #<fn [x]>"
+        );
+    }
+}
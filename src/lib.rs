@@ -0,0 +1,17 @@
+//! A MAL (Make a Lisp) interpreter.
+//!
+//! [`Interpreter`] is the entry point for embedding: it owns a root
+//! environment with the default builtins and evaluates source text in it.
+//! The lower-level pieces it is built from are public as well:
+//! [`reader`] parses source text into [`types::MalVal`] forms, [`eval`]
+//! evaluates them, [`printer`] writes values back out and [`env`](mod@env) holds
+//! variables and evaluation limits.
+
+pub mod eval;
+mod interpreter;
+pub mod printer;
+pub mod reader;
+pub mod types;
+
+pub use interpreter::{Error, Interpreter};
+pub use types::env;
@@ -1,47 +1,27 @@
-use eval::builtin;
+use mal::{
+    env::{Backend, EnvironmentBuilder, InterruptFlag},
+    reader, Interpreter,
+};
 use rustyline::error::ReadlineError;
 use rustyline::validate::{ValidationContext, ValidationResult, Validator};
 use rustyline_derive::{Completer, Helper, Highlighter, Hinter};
-use types::{
-    env::{Environment, EnvironmentBuilder},
-    EvalResult, MalAtom, MalVal,
-};
-
-mod eval;
-mod reader;
-mod types;
-
-fn read(input: &str) -> reader::Result<MalVal> {
-    let mut v = reader::read_str(input)?;
-    if !v.is_empty() {
-        Ok(v.swap_remove(0))
-    } else {
-        Ok(MalVal::Atom(MalAtom::Nil))
-    }
-}
-
-fn eval(ast: MalVal, env: &mut Environment) -> EvalResult<MalVal> {
-    eval::eval(ast, env)
-}
+use std::time::Duration;
 
-fn print(res: MalVal) {
-    let v = res.to_string();
-    println!("{}", v);
-}
+/// The REPL runs on its own thread so that deeply recursive programs have
+/// room to reach the evaluation depth limit instead of overflowing. See
+/// `mal::env::DEFAULT_MAX_DEPTH` for the stack each level takes.
+const REPL_STACK_SIZE: usize = 256 * 1024 * 1024;
+const REPL_MAX_DEPTH: usize = 10_000;
 
 fn print_err<T: std::fmt::Display>(e: T) {
     println!("error: {}", e);
 }
 
-fn rep(input: &str, env: &mut Environment) {
-    read(input).map_or_else(
-        |e| {
-            print_err(e);
-        },
-        |ast| {
-            eval(ast, env).map_or_else(print_err, print);
-        },
-    );
+fn rep(input: &str, interp: &Interpreter) {
+    match interp.eval_str(input) {
+        Ok(v) => println!("{}", v),
+        Err(e) => print_err(e),
+    }
 }
 
 #[derive(Completer, Helper, Highlighter, Hinter)]
@@ -52,7 +32,7 @@ impl Validator for InputValidator {
         use ValidationResult::{Incomplete, Invalid, Valid};
         let input = ctx.input();
 
-        let result = if let Err(parse_err) = read(input) {
+        let result = if let Err(parse_err) = reader::read_str(input) {
             match parse_err {
                 reader::ParseError::EOF => Incomplete,
                 _ => Invalid(Some(format!(" ---< {}", parse_err))),
@@ -65,22 +45,110 @@ impl Validator for InputValidator {
     }
 }
 
+struct Options {
+    backend: Backend,
+    optimize: bool,
+    io: bool,
+    max_depth: Option<usize>,
+    step_limit: Option<u64>,
+    time_limit: Option<Duration>,
+}
+
+const USAGE: &str = "usage: mal [--backend tree|bytecode] [--optimize] [--allow-io] \
+                     [--max-depth N] [--step-limit N] [--time-limit-ms N]";
+
+fn parse_num(arg: &str, value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", arg, value))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut opts = Options {
+        backend: Backend::default(),
+        optimize: false,
+        io: false,
+        max_depth: Some(REPL_MAX_DEPTH),
+        step_limit: None,
+        time_limit: None,
+    };
+    while let Some(arg) = args.next() {
+        if arg == "--optimize" {
+            opts.optimize = true;
+            continue;
+        }
+        if arg == "--allow-io" {
+            opts.io = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--backend" => {
+                opts.backend = match value.as_str() {
+                    "tree" => Backend::TreeWalk,
+                    "bytecode" => Backend::Bytecode,
+                    _ => return Err(format!("invalid value for {}: {}", arg, value)),
+                }
+            }
+            "--max-depth" => opts.max_depth = Some(parse_num(&arg, &value)? as usize),
+            "--step-limit" => opts.step_limit = Some(parse_num(&arg, &value)?),
+            "--time-limit-ms" => {
+                opts.time_limit = Some(Duration::from_millis(parse_num(&arg, &value)?))
+            }
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    Ok(opts)
+}
+
 fn main() {
+    let opts = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("error: {}\n{}", e, USAGE);
+        std::process::exit(2);
+    });
+    let repl = std::thread::Builder::new()
+        .name("repl".to_owned())
+        .stack_size(REPL_STACK_SIZE)
+        .spawn(move || repl(opts))
+        .expect("failed to start repl thread");
+    repl.join().expect("repl thread panicked");
+}
+
+fn repl(opts: Options) {
     let mut rl = rustyline::Editor::new();
     let helper = InputValidator {};
     rl.set_helper(Some(helper));
-    let mut env = EnvironmentBuilder::new()
-        .with_builtins(builtin::defaults())
-        .build();
+
+    // While a line is being edited the terminal is in raw mode and Ctrl-C
+    // reaches rustyline as a key press. During evaluation it arrives as
+    // SIGINT and only cancels the running evaluation.
+    let interrupt = InterruptFlag::new();
+    let handler_flag = interrupt.clone();
+    if let Err(e) = ctrlc::set_handler(move || handler_flag.interrupt()) {
+        print_err(e);
+    }
+
+    let interp = Interpreter::with_builder(
+        EnvironmentBuilder::new()
+            .with_backend(opts.backend)
+            .with_optimize(opts.optimize)
+            .with_io(opts.io)
+            .with_max_depth(opts.max_depth)
+            .with_step_limit(opts.step_limit)
+            .with_time_limit(opts.time_limit)
+            .with_interrupt(interrupt),
+    );
     loop {
         let readline = rl.readline("user> ");
         match readline {
             Ok(line) => {
-                rep(&line, &mut env);
+                rep(&line, &interp);
                 rl.add_history_entry(line.as_str());
             }
             Err(ReadlineError::Interrupted) => {
-                break;
+                continue;
             }
             Err(ReadlineError::Eof) => {
                 break;
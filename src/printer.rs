@@ -0,0 +1,99 @@
+//! Printing of values in a form other readers understand.
+
+use std::fmt::Write;
+
+use crate::types::{EvalError, EvalResult, MalAtom, MalVal};
+
+/// Prints `v` as EDN, escaping strings so that reading the result in
+/// `reader::Mode::Edn` gives back an equal value. Functions and regexes
+/// have no EDN form and are an error.
+pub fn pr_edn(v: &MalVal) -> EvalResult<String> {
+    let mut out = String::new();
+    write_edn(&mut out, v)?;
+    Ok(out)
+}
+
+fn write_edn(out: &mut String, v: &MalVal) -> EvalResult<()> {
+    match v {
+        MalVal::Atom(MalAtom::Str(s)) => write_str(out, s),
+        MalVal::Atom(a) => write!(out, "{}", a).unwrap(),
+        MalVal::List(seq, _) => write_seq(out, "(", seq, ")")?,
+        MalVal::Vector(seq, _) => write_seq(out, "[", seq, "]")?,
+        MalVal::AssocArray(map, _) => {
+            write_seq(out, "{", map.iter().flat_map(|(k, v)| vec![k, v]), "}")?
+        }
+        MalVal::Set(set, _) => write_seq(out, "#{", set, "}")?,
+        MalVal::Tagged(t) => {
+            write!(out, "#{} ", t.tag).unwrap();
+            write_edn(out, &t.form)?;
+        }
+        MalVal::Regex(_) => {
+            return Err(EvalError::Conversion(format!(
+                "cannot print regex {} as EDN",
+                v
+            )))
+        }
+        MalVal::Fn(f) => {
+            return Err(EvalError::Conversion(format!(
+                "cannot print function {} as EDN",
+                f
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn write_seq<'a>(
+    out: &mut String,
+    open: &str,
+    items: impl IntoIterator<Item = &'a MalVal>,
+    close: &str,
+) -> EvalResult<()> {
+    out.push_str(open);
+    for (i, v) in items.into_iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        write_edn(out, v)?;
+    }
+    out.push_str(close);
+    Ok(())
+}
+
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{read_str, read_str_with, Mode};
+
+    fn read_edn(input: &str) -> MalVal {
+        read_str_with(input, Mode::Edn).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_pr_edn() {
+        let input =
+            r#"[1 "a\"b\n\t\u0001" \c \newline :ns/k #{} (sym) #inst "2021-03-04T05:06:07Z"]"#;
+        let printed = pr_edn(&read_edn(input)).unwrap();
+        assert_eq!(printed, input);
+        assert_eq!(read_edn(&printed), read_edn(input));
+
+        let v = read_str("{:a [1 2]}").unwrap().remove(0);
+        assert_eq!(pr_edn(&v).unwrap(), "{:a [1 2]}");
+    }
+}
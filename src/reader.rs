@@ -1,5 +1,8 @@
-use crate::types::{MalAtom, MalVal};
-use std::iter::Peekable;
+use crate::types::{
+    char_name, literal_map, symbol::Symbol, MalAtom, MalVal, Meta, Pos, Regex, Span, Tagged,
+};
+use itertools::Itertools;
+use std::{cell::Cell, iter::Peekable, rc::Rc};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -11,14 +14,47 @@ pub enum ParseError {
     UnxpectedToken(String),
     #[error("Unexpected escapse sequence \\{0}")]
     UnknownEscapeSequence(char),
-    #[error("Unexpected newline")]
-    UnexpectedNewline,
+    #[error("Invalid Unicode escape \\u{{{0}}}")]
+    InvalidUnicodeEscape(String),
+    #[error("Map literal must contain an even number of forms")]
+    UnbalancedMap,
+    #[error("Duplicate key {0} in map literal")]
+    DuplicateKey(String),
+    #[error("Unknown character \\{0}")]
+    UnknownCharacter(String),
+    #[error("Invalid number {0}")]
+    InvalidNumber(String),
+    #[error("Floating-point numbers are not supported: {0}")]
+    UnsupportedFloat(String),
+    #[error("Unknown tag #{0}")]
+    UnknownTag(String),
+    #[error("Invalid value {1} for tag #{0}")]
+    InvalidTaggedValue(String, String),
+    #[error("Invalid regex: {0}")]
+    InvalidRegex(String),
 }
 
 pub type Result<T> = std::result::Result<T, ParseError>;
 
+/// The syntax accepted by the reader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// MAL source code.
+    Mal,
+    /// Extensible Data Notation, as produced by Clojure. Adds tagged
+    /// literals, `#_` discards and namespaced maps, and rejects the quoting
+    /// characters of MAL. Floating-point numbers are not supported, as MAL
+    /// has no type for them, and maps that repeat a key are rejected as in
+    /// both modes.
+    Edn,
+}
+
 pub fn read_str(input: &str) -> Result<Vec<MalVal>> {
-    let tokens = tokenize(input)?;
+    read_str_with(input, Mode::Mal)
+}
+
+pub fn read_str_with(input: &str, mode: Mode) -> Result<Vec<MalVal>> {
+    let tokens = lex(input, mode)?;
     let mut it = tokens.into_iter().peekable();
     let mut ret = Vec::new();
     while it.peek().is_some() {
@@ -31,9 +67,10 @@ pub fn read_str(input: &str) -> Result<Vec<MalVal>> {
 
 fn read_form<I>(it: &mut Peekable<I>) -> Result<Option<MalVal>>
 where
-    I: Iterator<Item = Token>,
+    I: Iterator<Item = (Token, Pos)>,
 {
-    if let Some(tok) = it.peek() {
+    if let Some((tok, start)) = it.peek() {
+        let start = *start;
         match tok {
             Token::SingleQuote => {
                 unimplemented!()
@@ -41,20 +78,70 @@ where
             Token::Tick => {
                 unimplemented!()
             }
+            Token::Caret => {
+                it.next();
+                // ^meta form is shorthand for (with-meta form meta)
+                let meta = read_next(it)?;
+                let form = read_next(it)?;
+                Ok(Some(MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym(Symbol::WITH_META)),
+                    form,
+                    meta,
+                ])))
+            }
             Token::LeftParen => {
                 it.next();
-                let seq = read_seq(it, Token::RightParen)?;
-                Ok(Some(MalVal::List(seq)))
+                let (seq, end) = read_seq(it, Token::RightParen)?;
+                Ok(Some(MalVal::List(
+                    seq.into_iter().collect(),
+                    Meta::at(Span { start, end }),
+                )))
             }
             Token::LeftBracket => {
                 it.next();
-                let seq = read_seq(it, Token::RightBracket)?;
-                Ok(Some(MalVal::Vector(seq)))
+                let (seq, _) = read_seq(it, Token::RightBracket)?;
+                Ok(Some(MalVal::vector(seq)))
             }
             Token::LeftCurly => {
                 it.next();
-                let seq = read_seq(it, Token::RightCurly)?;
-                Ok(Some(MalVal::AssocArray(seq)))
+                let (seq, _) = read_seq(it, Token::RightCurly)?;
+                if !seq.len().is_multiple_of(2) {
+                    return Err(ParseError::UnbalancedMap);
+                }
+                read_map(seq.into_iter().tuples()).map(Some)
+            }
+            Token::HashCurly => {
+                it.next();
+                let (seq, _) = read_seq(it, Token::RightCurly)?;
+                Ok(Some(MalVal::Set(
+                    seq.into_iter().collect(),
+                    Meta::default(),
+                )))
+            }
+            Token::Discard => {
+                it.next();
+                read_next(it)?;
+                Ok(None)
+            }
+            Token::NsMap(_) => {
+                let ns = match it.next() {
+                    Some((Token::NsMap(ns), _)) => ns,
+                    _ => unreachable!(),
+                };
+                match read_next(it)? {
+                    MalVal::AssocArray(map, _) => {
+                        read_map(map.into_iter().map(|(k, v)| (qualify(k, &ns), v))).map(Some)
+                    }
+                    v => Err(ParseError::UnxpectedToken(v.to_string())),
+                }
+            }
+            Token::Tag(_) => {
+                let tag = match it.next() {
+                    Some((Token::Tag(tag), _)) => tag,
+                    _ => unreachable!(),
+                };
+                let form = read_next(it)?;
+                read_tagged(tag, form).map(Some)
             }
             _ => Ok(read_atom(it)?),
         }
@@ -63,15 +150,121 @@ where
     }
 }
 
-fn read_seq<I>(it: &mut Peekable<I>, until: Token) -> Result<Vec<MalVal>>
+/// Reads the next form, skipping discarded ones.
+fn read_next<I>(it: &mut Peekable<I>) -> Result<MalVal>
 where
-    I: Iterator<Item = Token>,
+    I: Iterator<Item = (Token, Pos)>,
+{
+    while it.peek().is_some() {
+        if let Some(f) = read_form(it)? {
+            return Ok(f);
+        }
+    }
+    Err(ParseError::EOF)
+}
+
+fn read_map(entries: impl IntoIterator<Item = (MalVal, MalVal)>) -> Result<MalVal> {
+    match literal_map(entries) {
+        Ok(map) => Ok(MalVal::AssocArray(map, Meta::default())),
+        Err(key) => Err(ParseError::DuplicateKey(key.to_string())),
+    }
+}
+
+/// Adds the namespace of a `#:ns{}` map to a key without one. The `_`
+/// namespace removes it instead.
+fn qualify(key: MalVal, ns: &str) -> MalVal {
+    let name = match &key {
+        MalVal::Atom(MalAtom::Keyword(name)) => name,
+        _ => return key,
+    };
+    if let Some(name) = name.strip_prefix("_/") {
+        MalVal::Atom(MalAtom::Keyword(name.to_owned()))
+    } else if name.contains('/') {
+        key
+    } else {
+        MalVal::Atom(MalAtom::Keyword(format!("{}/{}", ns, name)))
+    }
+}
+
+/// Checks the form of a tagged literal. Tags other than `inst` and `uuid`
+/// must have a namespace, as the unqualified ones are reserved.
+fn read_tagged(tag: String, form: MalVal) -> Result<MalVal> {
+    let valid = match (tag.as_str(), &form) {
+        ("inst", MalVal::Atom(MalAtom::Str(s))) => is_inst(s),
+        ("uuid", MalVal::Atom(MalAtom::Str(s))) => is_uuid(s),
+        ("inst", _) | ("uuid", _) => false,
+        _ if tag.contains('/') => true,
+        _ => return Err(ParseError::UnknownTag(tag)),
+    };
+    if !valid {
+        return Err(ParseError::InvalidTaggedValue(tag, form.to_string()));
+    }
+    Ok(MalVal::Tagged(Rc::new(Tagged {
+        tag: tag.into(),
+        form,
+    })))
+}
+
+/// Whether `s` is an RFC 3339 timestamp, possibly truncated after the
+/// year, month, day or minutes.
+fn is_inst(s: &str) -> bool {
+    const TEMPLATE: &[u8] = b"dddd-dd-ddTdd:dd:dd";
+    let len = s
+        .bytes()
+        .zip(TEMPLATE)
+        .take_while(|&(c, &t)| {
+            if t == b'd' {
+                c.is_ascii_digit()
+            } else {
+                c == t
+            }
+        })
+        .count();
+    if ![4, 7, 10, 16, 19].contains(&len) {
+        return false;
+    }
+    let mut rest = &s[len..];
+    if len == 19 {
+        if let Some(frac) = rest.strip_prefix('.') {
+            let digits = frac.bytes().take_while(u8::is_ascii_digit).count();
+            if digits == 0 {
+                return false;
+            }
+            rest = &frac[digits..];
+        }
+    }
+    let offset = |r: &str| {
+        let b = r.as_bytes();
+        b.len() == 6
+            && (b[0] == b'+' || b[0] == b'-')
+            && b[1..3].iter().all(u8::is_ascii_digit)
+            && b[3] == b':'
+            && b[4..].iter().all(u8::is_ascii_digit)
+    };
+    rest.is_empty() || (len >= 16 && (rest == "Z" || offset(rest)))
+}
+
+/// Whether `s` is a UUID in its canonical hyphenated form.
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36
+        && s.bytes().enumerate().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == b'-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// Reads forms up to the token `until`, and returns them along with the
+/// position of that token.
+fn read_seq<I>(it: &mut Peekable<I>, until: Token) -> Result<(Vec<MalVal>, Pos)>
+where
+    I: Iterator<Item = (Token, Pos)>,
 {
     let mut res = Vec::new();
-    while let Some(v) = it.peek() {
+    while let Some((v, pos)) = it.peek() {
         if *v == until {
+            let end = *pos;
             it.next();
-            return Ok(res);
+            return Ok((res, end));
         }
         if let Some(f) = read_form(it)? {
             res.push(f)
@@ -82,18 +275,23 @@ where
 
 fn read_atom<I>(it: &mut Peekable<I>) -> Result<Option<MalVal>>
 where
-    I: Iterator<Item = Token>,
+    I: Iterator<Item = (Token, Pos)>,
 {
-    it.next().map_or(Ok(None), |tok| match tok {
+    it.next().map_or(Ok(None), |(tok, _)| match tok {
         Token::Int(i) => Ok(Some(MalVal::Atom(MalAtom::Int(i)))),
         Token::Str(s) => Ok(Some(MalVal::Atom(MalAtom::Str(s)))),
+        Token::Char(c) => Ok(Some(MalVal::Atom(MalAtom::Char(c)))),
+        Token::Regex(src) => regex::Regex::new(&src)
+            .map(|re| Some(MalVal::Regex(Regex(re))))
+            .map_err(|e| ParseError::InvalidRegex(e.to_string())),
         Token::Lit(l) => {
             let s: &str = &l;
             let atom = match s {
                 "nil" => MalAtom::Nil,
                 "true" => MalAtom::True,
                 "false" => MalAtom::False,
-                _ => MalAtom::Sym(l),
+                _ if s.len() > 1 && s.starts_with(':') => MalAtom::Keyword(s[1..].to_owned()),
+                _ => MalAtom::Sym(l.into()),
             };
             Ok(Some(MalVal::Atom(atom)))
         }
@@ -111,16 +309,46 @@ enum Token {
     RightCurly,
     SingleQuote,
     Tick,
+    Caret,
     Int(i64),
     Str(String),
     Lit(String),
+    Char(char),
+    /// `#{`, opening a set.
+    HashCurly,
+    /// `#_`, discarding the next form.
+    Discard,
+    /// `#:ns`, qualifying the keys of the following map.
+    NsMap(String),
+    /// `#tag`, tagging the next form.
+    Tag(String),
+    /// `#"..."`, the source of a regex.
+    Regex(String),
+}
+
+#[cfg(test)]
+fn tokenize(input: &str, mode: Mode) -> Result<Vec<Token>> {
+    Ok(lex(input, mode)?.into_iter().map(|(tok, _)| tok).collect())
 }
 
-fn tokenize(input: &str) -> Result<Vec<Token>> {
+/// Splits `input` into tokens, each with the position it starts at.
+fn lex(input: &str, mode: Mode) -> Result<Vec<(Token, Pos)>> {
     let mut result = Vec::new();
-    let mut it = input.chars().peekable();
+    let mut starts = Vec::new();
+    // The positions of the character last taken from the input and of the
+    // one after it. Only one character is ever peeked at, so right after
+    // the loop below takes a character, the former is where it starts.
+    let pos = Cell::new((Pos::START, Pos::START));
+    let mut it = input
+        .chars()
+        .inspect(|&c| {
+            let (_, next) = pos.get();
+            pos.set((next, next.advance(c)));
+        })
+        .peekable();
 
     while let Some(c) = it.next() {
+        let start = pos.get().0;
         match c {
             '(' => result.push(Token::LeftParen),
             ')' => result.push(Token::RightParen),
@@ -128,12 +356,39 @@ fn tokenize(input: &str) -> Result<Vec<Token>> {
             ']' => result.push(Token::RightBracket),
             '{' => result.push(Token::LeftCurly),
             '}' => result.push(Token::RightCurly),
+            // EDN has no quoting.
+            '\'' | '`' if mode == Mode::Edn => {
+                return Err(ParseError::UnxpectedToken(c.to_string()))
+            }
             '\'' => result.push(Token::SingleQuote),
             '`' => result.push(Token::Tick),
+            '^' => result.push(Token::Caret),
             '"' => {
                 let s = read_string(&mut it)?;
                 result.push(Token::Str(s));
             }
+            '#' if mode == Mode::Edn => result.push(read_dispatch(&mut it)?),
+            '#' if it.peek() == Some(&'{') => {
+                it.next();
+                result.push(Token::HashCurly);
+            }
+            '#' if it.peek() == Some(&'"') => {
+                it.next();
+                result.push(Token::Regex(read_regex(&mut it)?));
+            }
+            '\\' => result.push(Token::Char(read_char(&mut it)?)),
+            '+' | '-' if mode == Mode::Edn => {
+                if let Some(num) = read_edn_number(&mut it, c == '-', None)? {
+                    result.push(Token::Int(num))
+                } else {
+                    let lit = read_literal(&mut it, c);
+                    result.push(Token::Lit(lit));
+                }
+            }
+            '0'..='9' if mode == Mode::Edn => {
+                let num = read_edn_number(&mut it, false, Some(c))?;
+                result.push(Token::Int(num.unwrap()))
+            }
             ';' => {
                 let _ = read_comment(&mut it);
                 //result.push(Token::Comment(comment));
@@ -157,8 +412,9 @@ fn tokenize(input: &str) -> Result<Vec<Token>> {
                 }
             }
         }
+        starts.resize(result.len(), start);
     }
-    Ok(result)
+    Ok(result.into_iter().zip(starts).collect())
 }
 
 fn read_number<I: Iterator<Item = char>>(
@@ -186,6 +442,112 @@ fn read_number<I: Iterator<Item = char>>(
     }
 }
 
+/// Reads a number in EDN, which unlike MAL must end at a delimiter. An `N`
+/// suffix marking arbitrary precision is accepted and ignored, but the
+/// value must still fit in an `i64`.
+fn read_edn_number<I: Iterator<Item = char>>(
+    it: &mut Peekable<I>,
+    negative: bool,
+    first_digit: Option<char>,
+) -> Result<Option<i64>> {
+    let mut digits: String = first_digit.into_iter().collect();
+    while let Some(&c) = it.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        digits.push(c);
+        it.next();
+    }
+    if digits.is_empty() {
+        return Ok(None);
+    }
+    let literal = if negative {
+        format!("-{}", digits)
+    } else {
+        digits.clone()
+    };
+    // Negative numbers are accumulated as such so that `i64::MIN` fits.
+    let num = digits.chars().try_fold(0i64, |v, c| {
+        let d = c.to_digit(10).unwrap() as i64;
+        let v = v.checked_mul(10)?;
+        if negative {
+            v.checked_sub(d)
+        } else {
+            v.checked_add(d)
+        }
+    });
+    if it.peek() == Some(&'N') {
+        it.next();
+    }
+    match it.peek() {
+        Some(&c) if !is_delimiter(c) => {
+            let rest = read_literal(it, c);
+            let literal = format!("{}{}", literal, &rest[1..]);
+            if matches!(c, '.' | 'e' | 'E' | 'M') {
+                Err(ParseError::UnsupportedFloat(literal))
+            } else {
+                Err(ParseError::InvalidNumber(literal))
+            }
+        }
+        _ => num.map(Some).ok_or(ParseError::InvalidNumber(literal)),
+    }
+}
+
+/// Reads what follows a `#` in EDN.
+fn read_dispatch<I: Iterator<Item = char>>(it: &mut Peekable<I>) -> Result<Token> {
+    match it.next() {
+        Some('{') => Ok(Token::HashCurly),
+        Some('_') => Ok(Token::Discard),
+        Some(':') => match it.next() {
+            Some(c) if !is_delimiter(c) => Ok(Token::NsMap(read_literal(it, c))),
+            _ => Err(ParseError::UnxpectedToken("#:".to_owned())),
+        },
+        Some(c) if c.is_alphabetic() => Ok(Token::Tag(read_literal(it, c))),
+        Some(c) => Err(ParseError::UnxpectedToken(format!("#{}", c))),
+        None => Err(ParseError::EOF),
+    }
+}
+
+/// Reads a character literal after its backslash, e.g. `a`, `newline` or
+/// `u00e9`.
+fn read_char<I: Iterator<Item = char>>(it: &mut Peekable<I>) -> Result<char> {
+    let first = it.next().ok_or(ParseError::EOF)?;
+    let mut name = first.to_string();
+    if first.is_alphanumeric() {
+        while let Some(&c) = it.peek() {
+            if !c.is_alphanumeric() {
+                break;
+            }
+            name.push(c);
+            it.next();
+        }
+    }
+    if name.chars().count() == 1 {
+        return Ok(first);
+    }
+    let named = ['\n', ' ', '\t', '\r', '\u{8}', '\u{c}']
+        .iter()
+        .copied()
+        .find(|&c| char_name(c) == Some(&name));
+    named
+        .or_else(|| unicode_escape(name.strip_prefix('u')?))
+        .ok_or(ParseError::UnknownCharacter(name))
+}
+
+/// The character with the four hex digit code point `hex`.
+fn unicode_escape(hex: &str) -> Option<char> {
+    if hex.len() != 4 {
+        return None;
+    }
+    u32::from_str_radix(hex, 16)
+        .ok()
+        .and_then(std::char::from_u32)
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, ',' | '(' | ')' | '[' | ']' | '{' | '}' | '"' | ';')
+}
+
 fn read_comment<I: Iterator<Item = char>>(it: &mut Peekable<I>) -> String {
     let mut s = String::new();
     while let Some(&c) = it.peek() {
@@ -202,34 +564,81 @@ fn read_comment<I: Iterator<Item = char>>(it: &mut Peekable<I>) -> String {
     s
 }
 
+/// Reads a string literal after its opening quote. Strings may span lines
+/// and take the escapes `\"`, `\\`, `\n`, `\t`, `\r`, `\0`, `\uXXXX` and
+/// `\u{X...}`.
 fn read_string<I: Iterator<Item = char>>(it: &mut Peekable<I>) -> Result<String> {
     let mut s = String::new();
-    while let Some(&c) = it.peek() {
-        it.next();
+    while let Some(c) = it.next() {
         match c {
-            '\n' => return Err(ParseError::UnexpectedNewline),
             '\\' => {
-                if let Some(nc) = it.peek() {
-                    match nc {
-                        '"' => s.push('"'),
-                        'n' => s.push('\n'),
-                        '\\' => s.push('\\'),
-                        c => return Err(ParseError::UnknownEscapeSequence(*c)),
-                    }
-                    it.next();
-                } else {
-                    return Err(ParseError::EOF);
-                }
-            }
-            '"' => return Ok(s),
-            _ => {
+                let c = match it.next().ok_or(ParseError::EOF)? {
+                    '"' => '"',
+                    'n' => '\n',
+                    '\\' => '\\',
+                    't' => '\t',
+                    'r' => '\r',
+                    '0' => '\0',
+                    'u' => read_unicode_escape(it)?,
+                    c => return Err(ParseError::UnknownEscapeSequence(c)),
+                };
                 s.push(c);
             }
+            '"' => return Ok(s),
+            _ => s.push(c),
         }
     }
     Err(ParseError::EOF)
 }
 
+/// Reads the source of a regex literal after its opening quote. Escapes are
+/// left for the regex to interpret, except that `\"` stands for a quote
+/// rather than ending the literal.
+fn read_regex<I: Iterator<Item = char>>(it: &mut Peekable<I>) -> Result<String> {
+    let mut s = String::new();
+    while let Some(c) = it.next() {
+        match c {
+            '\\' => match it.next().ok_or(ParseError::EOF)? {
+                '"' => s.push('"'),
+                c => {
+                    s.push('\\');
+                    s.push(c);
+                }
+            },
+            '"' => return Ok(s),
+            _ => s.push(c),
+        }
+    }
+    Err(ParseError::EOF)
+}
+
+/// Reads the code point of a `\u` escape, given as four hex digits or as
+/// one to six in braces.
+fn read_unicode_escape<I: Iterator<Item = char>>(it: &mut Peekable<I>) -> Result<char> {
+    let mut hex = String::new();
+    if it.peek() == Some(&'{') {
+        it.next();
+        loop {
+            match it.next().ok_or(ParseError::EOF)? {
+                '}' => break,
+                c => hex.push(c),
+            }
+        }
+        if hex.len() > 6 {
+            return Err(ParseError::InvalidUnicodeEscape(hex));
+        }
+    } else {
+        hex.extend(it.take(4));
+        if hex.len() != 4 {
+            return Err(ParseError::InvalidUnicodeEscape(hex));
+        }
+    }
+    u32::from_str_radix(&hex, 16)
+        .ok()
+        .and_then(std::char::from_u32)
+        .ok_or(ParseError::InvalidUnicodeEscape(hex))
+}
+
 fn read_literal<I: Iterator<Item = char>>(it: &mut Peekable<I>, first_char: char) -> String {
     let mut s = String::new();
     s.push(first_char);
@@ -251,18 +660,20 @@ fn read_literal<I: Iterator<Item = char>>(it: &mut Peekable<I>, first_char: char
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::test_util::{int, kw};
 
     #[test]
+    #[allow(clippy::unnecessary_to_owned)]
     fn test_tokenize() {
         {
             let s = " , \n  \t ";
-            let v = tokenize(&s.to_string()).unwrap();
+            let v = tokenize(&s.to_string(), Mode::Mal).unwrap();
             assert_eq!(v, vec![]);
         }
 
         {
             let s = "  ( ,,, ) [ ]}  \n  \t {";
-            let v = tokenize(&s.to_string()).unwrap();
+            let v = tokenize(&s.to_string(), Mode::Mal).unwrap();
             assert_eq!(
                 v,
                 vec![
@@ -278,7 +689,7 @@ mod tests {
 
         {
             let s = "  (+ asdf)";
-            let v = tokenize(&s.to_string()).unwrap();
+            let v = tokenize(&s.to_string(), Mode::Mal).unwrap();
             assert_eq!(
                 v,
                 vec![
@@ -292,7 +703,7 @@ mod tests {
 
         {
             let s = "  (+ 0 12 345 6789 -1 -12 -123)";
-            let v = tokenize(&s.to_string()).unwrap();
+            let v = tokenize(&s.to_string(), Mode::Mal).unwrap();
             assert_eq!(
                 v,
                 vec![
@@ -312,7 +723,7 @@ mod tests {
 
         {
             let s = "  (+ \"asd\\\"f\")";
-            let v = tokenize(&s.to_string()).unwrap();
+            let v = tokenize(&s.to_string(), Mode::Mal).unwrap();
             assert_eq!(
                 v,
                 vec![
@@ -326,18 +737,34 @@ mod tests {
 
         {
             let s = "\"a\\nb\"";
-            let v = tokenize(&s.to_string()).unwrap();
+            let v = tokenize(&s.to_string(), Mode::Mal).unwrap();
             assert_eq!(v, vec![Token::Str("a\nb".into()),]);
         }
         {
             let s = "\"a\\\\b\"";
-            let v = tokenize(&s.to_string()).unwrap();
+            let v = tokenize(&s.to_string(), Mode::Mal).unwrap();
             assert_eq!(v, vec![Token::Str("a\\b".into()),]);
         }
 
+        {
+            let s = "^{:doc \"x\"} a^b";
+            let v = tokenize(s, Mode::Mal).unwrap();
+            assert_eq!(
+                v,
+                vec![
+                    Token::Caret,
+                    Token::LeftCurly,
+                    Token::Lit(":doc".into()),
+                    Token::Str("x".into()),
+                    Token::RightCurly,
+                    Token::Lit("a^b".into()),
+                ]
+            );
+        }
+
         {
             let s = " ; ()[]}\t{\n()";
-            let v = tokenize(&s.to_string()).unwrap();
+            let v = tokenize(&s.to_string(), Mode::Mal).unwrap();
             assert_eq!(
                 v,
                 vec![
@@ -350,6 +777,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_read_str() {
         {
             let s = r#"
@@ -364,7 +792,7 @@ mod tests {
             let v = read_str(&s).unwrap();
             assert_eq!(
                 *v.first().unwrap(),
-                MalVal::List(vec![
+                MalVal::list(vec![
                     MalVal::Atom(MalAtom::Sym("println".into())),
                     MalVal::Atom(MalAtom::Str("hello".into()))
                 ])
@@ -380,11 +808,11 @@ mod tests {
             assert_eq!(
                 v,
                 vec![
-                    MalVal::List(vec![
+                    MalVal::list(vec![
                         MalVal::Atom(MalAtom::Sym("println".into())),
                         MalVal::Atom(MalAtom::Str("hello".into()))
                     ]),
-                    MalVal::List(vec![
+                    MalVal::list(vec![
                         MalVal::Atom(MalAtom::Sym("print-line".into())),
                         MalVal::Atom(MalAtom::Str("world".into()))
                     ]),
@@ -400,11 +828,11 @@ mod tests {
             let v = read_str(&s).unwrap();
             assert_eq!(
                 v,
-                vec![MalVal::List(vec![
+                vec![MalVal::list(vec![
                     MalVal::Atom(MalAtom::Sym("fun1!".into())),
                     MalVal::Atom(MalAtom::Int(2)),
                     MalVal::Atom(MalAtom::Str("hello".into())),
-                    MalVal::List(vec![
+                    MalVal::list(vec![
                         MalVal::Atom(MalAtom::Sym("fun2?".into())),
                         MalVal::Atom(MalAtom::Int(3)),
                         MalVal::Atom(MalAtom::Str("world".into())),
@@ -420,12 +848,219 @@ mod tests {
             let v = read_str(&s).unwrap();
             assert_eq!(
                 *v.first().unwrap(),
-                MalVal::List(vec![
+                MalVal::list(vec![
                     MalVal::Atom(MalAtom::Nil),
                     MalVal::Atom(MalAtom::True),
                     MalVal::Atom(MalAtom::False),
                 ])
             );
         }
+
+        {
+            let s = r#"
+            (:kw : ^{:doc "hello"} [1])
+            "#;
+            let v = read_str(s).unwrap();
+            assert_eq!(
+                *v.first().unwrap(),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Keyword("kw".into())),
+                    MalVal::Atom(MalAtom::Sym(":".into())),
+                    MalVal::list(vec![
+                        MalVal::Atom(MalAtom::Sym("with-meta".into())),
+                        MalVal::vector(vec![MalVal::Atom(MalAtom::Int(1))]),
+                        MalVal::assoc_array(vec![(
+                            MalVal::Atom(MalAtom::Keyword("doc".into())),
+                            MalVal::Atom(MalAtom::Str("hello".into())),
+                        )]),
+                    ]),
+                ])
+            );
+        }
+        {
+            let s = "^{:a 1}";
+            assert!(matches!(read_str(s), Err(ParseError::EOF)));
+        }
+        {
+            let s = "{:a 1 :b}";
+            assert!(matches!(read_str(s), Err(ParseError::UnbalancedMap)));
+        }
+        {
+            let s = "{:a 1 :a 2}";
+            assert!(matches!(read_str(s), Err(ParseError::DuplicateKey(k)) if k == ":a"));
+        }
+        {
+            let s = r#"
+            "a\tb\r\0\u00e9\u{1F600}
+c" \a \newline \( \u0041
+            "#;
+            let v = read_str(s).unwrap();
+            assert_eq!(
+                v,
+                vec![
+                    MalVal::Atom(MalAtom::Str("a\tb\r\0\u{e9}\u{1F600}\nc".into())),
+                    MalVal::Atom(MalAtom::Char('a')),
+                    MalVal::Atom(MalAtom::Char('\n')),
+                    MalVal::Atom(MalAtom::Char('(')),
+                    MalVal::Atom(MalAtom::Char('A')),
+                ]
+            );
+            assert_eq!(v[2].to_string(), "\\newline");
+        }
+        {
+            for s in &[r#""\u{110000}""#, r#""\u{1234567}""#, r#""\u12""#] {
+                assert!(
+                    matches!(read_str(s), Err(ParseError::InvalidUnicodeEscape(_))),
+                    "{}",
+                    s
+                );
+            }
+            assert!(matches!(
+                read_str(r#""\q""#),
+                Err(ParseError::UnknownEscapeSequence('q'))
+            ));
+            assert!(matches!(read_str(r#""\u{41"#), Err(ParseError::EOF)));
+        }
+        {
+            let v = read_str(r#"#"\d+\"\n" #"""#).unwrap();
+            assert_eq!(v[0].to_string(), r#"#"\d+\"\n""#);
+            assert_eq!(v[1].to_string(), r#"#"""#);
+            assert_eq!(v[0], read_str(r#"#"\d+\"\n""#).unwrap()[0]);
+            assert!(matches!(
+                read_str(r#"#"(""#),
+                Err(ParseError::InvalidRegex(_))
+            ));
+            assert!(matches!(read_str(r#"#"\""#), Err(ParseError::EOF)));
+        }
+        {
+            let s = "#{1 #{}}";
+            let v = read_str(s).unwrap();
+            assert_eq!(
+                v[0],
+                MalVal::Set(
+                    vec![
+                        MalVal::Atom(MalAtom::Int(1)),
+                        MalVal::Set(Default::default(), Meta::default()),
+                    ]
+                    .into_iter()
+                    .collect(),
+                    Meta::default(),
+                )
+            );
+            assert_eq!(v[0].to_string(), "#{1 #{}}");
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let span = |v: &MalVal| match v {
+            MalVal::List(_, meta) => meta.span().map(|span| span.to_string()),
+            _ => None,
+        };
+        let v = read_str("(a \"é\" (b))\n  (c\n d)").unwrap();
+        assert_eq!(span(&v[0]).as_deref(), Some("1:1-1:11"));
+        match &v[0] {
+            MalVal::List(seq, _) => assert_eq!(span(&seq[2]).as_deref(), Some("1:8-1:10")),
+            v => panic!("expected a list, got {}", v),
+        }
+        assert_eq!(span(&v[1]).as_deref(), Some("2:3-3:3"));
+    }
+
+    #[test]
+    fn test_read_edn() {
+        let read = |s: &str| read_str_with(s, Mode::Edn);
+
+        assert_eq!(
+            read("#{1 #_ 2 3} +4 5N \\a \\space \\u00e9").unwrap(),
+            vec![
+                MalVal::Set(vec![int(1), int(3)].into_iter().collect(), Meta::default()),
+                int(4),
+                int(5),
+                MalVal::Atom(MalAtom::Char('a')),
+                MalVal::Atom(MalAtom::Char(' ')),
+                MalVal::Atom(MalAtom::Char('\u{e9}')),
+            ]
+        );
+        assert_eq!(
+            read("#:ns{:a 1 :other/b 2 :_/c 3}").unwrap()[0],
+            MalVal::assoc_array(vec![
+                (kw("ns/a"), int(1)),
+                (kw("other/b"), int(2)),
+                (kw("c"), int(3)),
+            ])
+        );
+        assert!(matches!(
+            read("#:ns{:a 1 :ns/a 2}"),
+            Err(ParseError::DuplicateKey(k)) if k == ":ns/a"
+        ));
+        assert_eq!(
+            read("\"a\\tb\\u0041\nc\"").unwrap()[0],
+            MalVal::Atom(MalAtom::Str("a\tbA\nc".into()))
+        );
+
+        let tagged = read("#app/point [1 2]").unwrap().remove(0);
+        assert_eq!(tagged.to_string(), "#app/point [1 2]");
+        for inst in &["2021", "2021-03-04", "2021-03-04T05:06:07.123+01:00"] {
+            assert!(read(&format!("#inst \"{}\"", inst)).is_ok(), "{}", inst);
+        }
+        assert!(read("#uuid \"f81d4fae-7dec-11d0-a765-00a0c91e6bf6\"").is_ok());
+
+        assert!(matches!(
+            read("#inst \"2021-3-4\""),
+            Err(ParseError::InvalidTaggedValue(..))
+        ));
+        assert!(matches!(
+            read("#uuid \"f81d4fae\""),
+            Err(ParseError::InvalidTaggedValue(..))
+        ));
+        assert!(matches!(
+            read("#point [1 2]"),
+            Err(ParseError::UnknownTag(_))
+        ));
+        for float in &["1.5", "-1.5", "1e3", "2M"] {
+            assert!(
+                matches!(read(float), Err(ParseError::UnsupportedFloat(_))),
+                "{}",
+                float
+            );
+        }
+        assert!(matches!(read("12ab"), Err(ParseError::InvalidNumber(_))));
+        assert_eq!(
+            read("-9223372036854775808 9223372036854775807N").unwrap(),
+            vec![int(i64::MIN), int(i64::MAX)]
+        );
+        for big in &[
+            "12345678901234567890N",
+            "9223372036854775808",
+            "-9223372036854775809",
+        ] {
+            assert!(
+                matches!(read(big), Err(ParseError::InvalidNumber(_))),
+                "{}",
+                big
+            );
+        }
+        assert!(matches!(
+            read("{:a 1 :a 2}"),
+            Err(ParseError::DuplicateKey(k)) if k == ":a"
+        ));
+        for quoted in &["'x", "`x", "(a 'b)"] {
+            assert!(
+                matches!(read(quoted), Err(ParseError::UnxpectedToken(_))),
+                "{}",
+                quoted
+            );
+        }
+        assert!(matches!(
+            read("\\bell"),
+            Err(ParseError::UnknownCharacter(_))
+        ));
+        assert!(matches!(read("[1 #_]"), Err(ParseError::UnxpectedToken(_))));
+
+        // MAL mode leaves everything but sets to the literal reader.
+        assert_eq!(
+            read_str("#a").unwrap()[0],
+            MalVal::Atom(MalAtom::Sym("#a".into()))
+        );
     }
 }
@@ -1,37 +1,192 @@
-use std::fmt::Display;
+use itertools::Itertools;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::Display,
+    hash::{BuildHasherDefault, Hash, Hasher},
+    rc::Rc,
+};
 use thiserror::Error;
 
-use self::env::Environment;
+use self::{env::Environment, symbol::Symbol};
+use crate::eval::{analyze::Expr, compile::Chunk};
 
+pub mod convert;
+#[cfg(feature = "serde")]
+pub mod de;
 pub mod env;
+#[cfg(feature = "serde")]
+pub mod ser;
+pub mod symbol;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum MalVal {
     Atom(MalAtom),
-    List(Vec<MalVal>),
-    Vector(Vec<MalVal>),
-    AssocArray(Vec<MalVal>),
-    Fn(Box<MalFn>),
+    List(Seq, Meta),
+    Vector(Seq, Meta),
+    AssocArray(Map, Meta),
+    Set(Set, Meta),
+    Tagged(Rc<Tagged>),
+    Regex(Regex),
+    Fn(Rc<MalFn>),
 }
 
+/// Persistent sequence backing lists and vectors. Clones are O(1) and
+/// updates share structure with the original.
+pub type Seq = im::Vector<MalVal>;
+
+/// Persistent hash map backing assoc arrays. A fixed hasher keeps the
+/// iteration order, and therefore printing, stable between runs.
+pub type Map = im::HashMap<MalVal, MalVal, BuildHasherDefault<DefaultHasher>>;
+
+/// Persistent hash set, hashed like `Map` for a stable printing order.
+pub type Set = im::HashSet<MalVal, BuildHasherDefault<DefaultHasher>>;
+
+/// A tagged literal such as `#inst "2021-03-04"` or `#app/point [1 2]`,
+/// kept as the tag and the form that follows it.
 #[derive(Debug, Clone, PartialEq)]
+pub struct Tagged {
+    pub tag: Symbol,
+    pub form: MalVal,
+}
+
+/// A compiled regular expression, as read from `#"..."`. Regexes are equal
+/// when their source is.
+#[derive(Debug, Clone)]
+pub struct Regex(pub regex::Regex);
+
+impl PartialEq for Regex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// Metadata attached to a value with `with-meta`. Lists read from source
+/// also remember where they were read from. Metadata never takes part in
+/// equality, so two values that differ only in their metadata compare
+/// equal.
+#[derive(Debug, Clone, Default)]
+pub struct Meta(Option<Rc<MetaData>>);
+
+#[derive(Debug)]
+struct MetaData {
+    value: Option<MalVal>,
+    span: Option<Span>,
+}
+
+impl Meta {
+    pub fn new(meta: MalVal) -> Self {
+        Meta(Some(Rc::new(MetaData {
+            value: Some(meta),
+            span: None,
+        })))
+    }
+
+    /// Metadata recording only the source text a form was read from.
+    pub fn at(span: Span) -> Self {
+        Meta(Some(Rc::new(MetaData {
+            value: None,
+            span: Some(span),
+        })))
+    }
+
+    pub fn get(&self) -> Option<&MalVal> {
+        self.0.as_ref().and_then(|m| m.value.as_ref())
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.0.as_ref().and_then(|m| m.span)
+    }
+}
+
+impl PartialEq for Meta {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+/// A position in source text. Lines and columns count from 1, and columns
+/// count characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pos {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Pos {
+    pub const START: Pos = Pos { line: 1, column: 1 };
+
+    /// The position of the character after `c`, if `c` is at this one.
+    pub fn advance(self, c: char) -> Pos {
+        if c == '\n' {
+            Pos {
+                line: self.line + 1,
+                column: 1,
+            }
+        } else {
+            Pos {
+                column: self.column + 1,
+                ..self
+            }
+        }
+    }
+}
+
+/// The source text of a form, from its first to its last character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: Pos,
+    pub end: Pos,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}-{}:{}",
+            self.start.line, self.start.column, self.end.line, self.end.column
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum MalAtom {
     Nil,
     True,
     False,
-    Sym(String),
+    Sym(Symbol),
+    Keyword(String),
     Str(String),
     Int(i64),
+    Char(char),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MalFn {
     pub env: Environment,
     pub body: MalVal,
-    pub binds: Vec<String>,
+    pub binds: Vec<Symbol>,
+    /// Slot names of a call frame, see `Lambda::names`.
+    pub names: Rc<[Symbol]>,
+    pub code: Rc<Expr>,
+    /// Bytecode of the body, if the function was created by the VM.
+    pub chunk: Option<Rc<Chunk>>,
+    pub name: Option<String>,
+    pub doc: Option<String>,
+    /// Where the `fn*` form was read from, if it came from source text.
+    pub span: Option<Span>,
+    pub meta: Meta,
 }
 
-pub type NativeFn = fn(Vec<MalVal>) -> EvalResult<MalVal>;
+/// A builtin implemented in Rust. See `convert::IntoNativeFn` for creating
+/// one from a function with typed arguments.
+pub type NativeFn = Rc<dyn Fn(Vec<MalVal>) -> EvalResult<MalVal>>;
+
+/// A form evaluated by Rust code instead of by applying a function. It
+/// receives the whole unevaluated form, head included, and the environment
+/// it appears in.
+pub type SpecialForm = fn(&Environment, &Seq) -> EvalResult<MalVal>;
 
 pub type EvalResult<T> = std::result::Result<T, EvalError>;
 
@@ -45,15 +200,175 @@ pub enum EvalError {
     NotASymbol,
     #[error("Not a list")]
     NotAList,
+    #[error("Expected {0} but got {1}")]
+    UnexpectedType(&'static str, String),
+    #[error("Conversion failed: {0}")]
+    Conversion(String),
+    #[error("Invalid JSON: {0}")]
+    Json(String),
+    #[error("Invalid EDN: {0}")]
+    Edn(String),
+    #[error("Index {0} out of bounds for length {1}")]
+    IndexOutOfBounds(i64, usize),
+    #[error("Invalid format: {0}")]
+    Format(String),
+    #[error("Invalid regex: {0}")]
+    Regex(String),
+    #[error("Duplicate key {0} in map literal")]
+    DuplicateKey(String),
+    #[error("I/O error: {0}")]
+    Io(String),
     #[error("Function {0} not defined")]
     FunctionUndefined(String),
     #[error("Bad function designator {0}")]
     BadFunctionDesignator(String),
     #[error("Invalid arguments provided")]
     InvalidArgs,
+    #[error("Recur used outside of loop")]
+    RecurOutsideLoop,
+    #[error("Recur not in tail position")]
+    RecurNotInTailPosition,
+    #[error("Recur expected {0} arguments but got {1}")]
+    RecurArityMismatch(usize, usize),
+    #[error("Stack overflow: evaluation depth reached {0}")]
+    StackOverflow(usize),
+    #[error("Step limit of {0} evaluation steps exceeded")]
+    StepLimitExceeded(u64),
+    #[error("Evaluation deadline exceeded")]
+    DeadlineExceeded,
+    #[error("Interrupted")]
+    Interrupted,
+    #[error("{0}{1}")]
+    Traced(Box<EvalError>, StackTrace),
+}
+
+/// Maximum number of frames kept in a stack trace. Frames beyond this are
+/// only counted so that runaway recursion produces a readable trace.
+pub const MAX_TRACE_FRAMES: usize = 32;
+
+/// The MAL functions that were being applied when an error occurred,
+/// innermost first.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StackTrace {
+    pub frames: Vec<Frame>,
+    pub omitted: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub name: Option<String>,
+    pub call_site: MalVal,
+}
+
+impl Frame {
+    pub fn new(name: Option<String>, call_site: MalVal) -> Self {
+        Frame { name, call_site }
+    }
+}
+
+impl EvalError {
+    /// Records that this error propagated out of `frame`.
+    pub fn with_frame(self, frame: Frame) -> EvalError {
+        let (err, mut trace) = match self {
+            EvalError::Traced(err, trace) => (err, trace),
+            err => (Box::new(err), StackTrace::default()),
+        };
+        if trace.frames.len() < MAX_TRACE_FRAMES {
+            trace.frames.push(frame);
+        } else {
+            trace.omitted += 1;
+        }
+        EvalError::Traced(err, trace)
+    }
+
+    /// Counts `n` more frames as omitted from the trace.
+    pub fn with_omitted_frames(self, n: usize) -> EvalError {
+        if n == 0 {
+            return self;
+        }
+        let (err, mut trace) = match self {
+            EvalError::Traced(err, trace) => (err, trace),
+            err => (Box::new(err), StackTrace::default()),
+        };
+        trace.omitted += n;
+        EvalError::Traced(err, trace)
+    }
+}
+
+impl Display for StackTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for frame in &self.frames {
+            write!(f, "\n  at {}", frame)?;
+        }
+        if self.omitted > 0 {
+            write!(f, "\n  ... {} more frames", self.omitted)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}: {}", name, self.call_site),
+            None => write!(f, "<anonymous>: {}", self.call_site),
+        }
+    }
 }
 
 impl MalVal {
+    pub fn list<T: Into<Seq>>(seq: T) -> Self {
+        MalVal::List(seq.into(), Meta::default())
+    }
+
+    pub fn vector<T: Into<Seq>>(seq: T) -> Self {
+        MalVal::Vector(seq.into(), Meta::default())
+    }
+
+    pub fn assoc_array<T>(entries: T) -> Self
+    where
+        T: IntoIterator<Item = (MalVal, MalVal)>,
+    {
+        MalVal::AssocArray(entries.into_iter().collect(), Meta::default())
+    }
+
+    pub fn meta(&self) -> Option<&MalVal> {
+        match self {
+            MalVal::List(_, meta)
+            | MalVal::Vector(_, meta)
+            | MalVal::AssocArray(_, meta)
+            | MalVal::Set(_, meta) => meta.get(),
+            MalVal::Fn(f) => f.meta.get(),
+            MalVal::Atom(_) | MalVal::Tagged(_) | MalVal::Regex(_) => None,
+        }
+    }
+
+    /// Returns a copy of this value carrying `meta`, or `None` if this kind
+    /// of value cannot hold metadata.
+    pub fn with_meta(self, meta: MalVal) -> Option<MalVal> {
+        let meta = Meta::new(meta);
+        match self {
+            MalVal::List(seq, _) => Some(MalVal::List(seq, meta)),
+            MalVal::Vector(seq, _) => Some(MalVal::Vector(seq, meta)),
+            MalVal::AssocArray(map, _) => Some(MalVal::AssocArray(map, meta)),
+            MalVal::Set(set, _) => Some(MalVal::Set(set, meta)),
+            MalVal::Fn(mut f) => {
+                Rc::make_mut(&mut f).meta = meta;
+                Some(MalVal::Fn(f))
+            }
+            MalVal::Atom(_) | MalVal::Tagged(_) | MalVal::Regex(_) => None,
+        }
+    }
+
+    /// Looks up `key` in an assoc array.
+    pub fn get(&self, key: &MalVal) -> Option<&MalVal> {
+        if let MalVal::AssocArray(map, _) = self {
+            map.get(key)
+        } else {
+            None
+        }
+    }
+
     pub fn is_truthy(&self) -> bool {
         !matches!(
             self,
@@ -62,6 +377,63 @@ impl MalVal {
     }
 }
 
+// Values are usable as map keys. Metadata is ignored just like in
+// PartialEq, and functions hash by signature since their environments
+// cannot be hashed.
+impl Eq for MalVal {}
+
+impl Hash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            // Symbol ids depend on interning order, so hash the name to
+            // keep map iteration order stable between runs.
+            MalVal::Atom(MalAtom::Sym(s)) => s.as_str().hash(state),
+            MalVal::Atom(a) => a.hash(state),
+            MalVal::List(seq, _) | MalVal::Vector(seq, _) => seq.hash(state),
+            MalVal::AssocArray(map, _) => hash_unordered(map.iter(), state),
+            MalVal::Set(set, _) => hash_unordered(set.iter(), state),
+            MalVal::Tagged(t) => {
+                t.tag.as_str().hash(state);
+                t.form.hash(state);
+            }
+            MalVal::Regex(re) => re.0.as_str().hash(state),
+            MalVal::Fn(f) => {
+                f.name.hash(state);
+                for b in &f.binds {
+                    b.as_str().hash(state);
+                }
+            }
+        }
+    }
+}
+
+/// Collects the entries of a map literal. Unlike `assoc`, a literal may
+/// not repeat a key, so the first key that repeats is returned as an error.
+pub fn literal_map(entries: impl IntoIterator<Item = (MalVal, MalVal)>) -> Result<Map, MalVal> {
+    let mut map = Map::default();
+    for (k, v) in entries {
+        if map.contains_key(&k) {
+            return Err(k);
+        }
+        map.insert(k, v);
+    }
+    Ok(map)
+}
+
+/// Combines element hashes commutatively so that equal maps and sets hash
+/// equally regardless of their internal layout.
+fn hash_unordered<T: Hash, H: Hasher>(items: impl ExactSizeIterator<Item = T>, state: &mut H) {
+    items.len().hash(state);
+    let mut sum: u64 = 0;
+    for item in items {
+        let mut h = DefaultHasher::new();
+        item.hash(&mut h);
+        sum = sum.wrapping_add(h.finish());
+    }
+    sum.hash(state);
+}
+
 impl From<MalVal> for bool {
     fn from(v: MalVal) -> Self {
         v.is_truthy()
@@ -96,29 +468,57 @@ impl Display for MalVal {
             MalVal::Atom(a) => {
                 write!(f, "{}", a)?;
             }
-            MalVal::List(seq) => {
+            MalVal::List(seq, _) => {
                 f.write_str("(")?;
                 fmt_seq(f, seq)?;
                 f.write_str(")")?;
             }
-            MalVal::Vector(seq) => {
+            MalVal::Vector(seq, _) => {
                 f.write_str("[")?;
                 fmt_seq(f, seq)?;
                 f.write_str("]")?;
             }
-            MalVal::AssocArray(seq) => {
+            MalVal::AssocArray(map, _) => {
                 f.write_str("{")?;
-                fmt_seq(f, seq)?;
+                fmt_seq(f, map.iter().flat_map(|(k, v)| vec![k, v]))?;
                 f.write_str("}")?;
             }
-            MalVal::Fn(_) => {
-                write!(f, "#<function>")?;
+            MalVal::Set(set, _) => {
+                f.write_str("#{")?;
+                fmt_seq(f, set)?;
+                f.write_str("}")?;
+            }
+            MalVal::Tagged(t) => {
+                write!(f, "#{} {}", t.tag, t.form)?;
+            }
+            MalVal::Regex(re) => {
+                write!(f, "#\"{}\"", re.0.as_str().replace('"', "\\\""))?;
+            }
+            MalVal::Fn(func) => {
+                write!(f, "{}", func)?;
             }
         }
         Ok(())
     }
 }
 
+impl MalFn {
+    /// The name and parameter list, e.g. `add [a b]`.
+    pub fn signature(&self) -> String {
+        let params = self.binds.iter().join(" ");
+        match &self.name {
+            Some(name) => format!("{} [{}]", name, params),
+            None => format!("[{}]", params),
+        }
+    }
+}
+
+impl Display for MalFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#<fn {}>", self.signature())
+    }
+}
+
 fn fmt_seq<T>(f: &mut std::fmt::Formatter, seq: T) -> std::fmt::Result
 where
     T: IntoIterator,
@@ -142,12 +542,30 @@ impl Display for MalAtom {
             MalAtom::True => write!(f, "true"),
             MalAtom::False => write!(f, "false"),
             MalAtom::Sym(s) => write!(f, "{}", s),
+            MalAtom::Keyword(k) => write!(f, ":{}", k),
             MalAtom::Str(s) => write!(f, "\"{}\"", s),
             MalAtom::Int(i) => write!(f, "{}", i),
+            MalAtom::Char(c) => match char_name(*c) {
+                Some(name) => write!(f, "\\{}", name),
+                None => write!(f, "\\{}", c),
+            },
         }
     }
 }
 
+/// The name a character literal uses for `c`, as in `\newline`.
+pub fn char_name(c: char) -> Option<&'static str> {
+    match c {
+        '\n' => Some("newline"),
+        ' ' => Some("space"),
+        '\t' => Some("tab"),
+        '\r' => Some("return"),
+        '\u{8}' => Some("backspace"),
+        '\u{c}' => Some("formfeed"),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -155,16 +573,16 @@ mod tests {
     #[test]
     fn test_display_malval() {
         {
-            let v = MalVal::List(vec![]);
+            let v = MalVal::list(vec![]);
 
             assert_eq!(v.to_string(), "()")
         }
         {
-            let v = MalVal::List(vec![
+            let v = MalVal::list(vec![
                 MalVal::Atom(MalAtom::Nil),
                 MalVal::Atom(MalAtom::True),
                 MalVal::Atom(MalAtom::False),
-                MalVal::List(vec![]),
+                MalVal::list(vec![]),
                 MalVal::Atom(MalAtom::Sym("hello".into())),
                 MalVal::Atom(MalAtom::Str("world".into())),
                 MalVal::Atom(MalAtom::Int(123)),
@@ -174,16 +592,16 @@ mod tests {
         }
 
         {
-            let v = MalVal::Vector(vec![]);
+            let v = MalVal::vector(vec![]);
 
             assert_eq!(v.to_string(), "[]")
         }
         {
-            let v = MalVal::Vector(vec![
+            let v = MalVal::vector(vec![
                 MalVal::Atom(MalAtom::Nil),
                 MalVal::Atom(MalAtom::True),
                 MalVal::Atom(MalAtom::False),
-                MalVal::List(vec![]),
+                MalVal::list(vec![]),
                 MalVal::Atom(MalAtom::Sym("hello".into())),
                 MalVal::Atom(MalAtom::Str("world".into())),
                 MalVal::Atom(MalAtom::Int(123)),
@@ -193,10 +611,62 @@ mod tests {
         }
 
         {
-            let v = MalVal::AssocArray(vec![]);
+            let v = MalVal::assoc_array(vec![]);
 
             assert_eq!(v.to_string(), "{}")
         }
+        {
+            let v = MalVal::assoc_array(vec![(
+                MalVal::Atom(MalAtom::Keyword("a".into())),
+                MalVal::Atom(MalAtom::Int(1)),
+            )]);
+
+            assert_eq!(v.to_string(), "{:a 1}")
+        }
+    }
+
+    #[test]
+    fn test_display_trace() {
+        let err = EvalError::NotANumber
+            .with_frame(Frame::new(
+                Some("add".to_owned()),
+                MalVal::list(vec![
+                    MalVal::Atom(MalAtom::Sym("add".into())),
+                    MalVal::Atom(MalAtom::Int(1)),
+                    MalVal::Atom(MalAtom::Str("x".into())),
+                ]),
+            ))
+            .with_frame(Frame::new(
+                None,
+                MalVal::list(vec![MalVal::Atom(MalAtom::Sym("f".into()))]),
+            ));
+
+        assert_eq!(
+            err.to_string(),
+            "Not a number\n  at add: (add 1 \"x\")\n  at <anonymous>: (f)"
+        );
+
+        let mut err = EvalError::InvalidArgs;
+        for _ in 0..MAX_TRACE_FRAMES + 3 {
+            err = err.with_frame(Frame::new(None, MalVal::list(vec![])));
+        }
+        assert!(err.to_string().ends_with("\n  ... 3 more frames"));
+    }
+
+    #[test]
+    fn test_meta_ignored_by_equality() {
+        let meta = MalVal::assoc_array(vec![(
+            MalVal::Atom(MalAtom::Keyword("doc".into())),
+            MalVal::Atom(MalAtom::Str("hi".into())),
+        )]);
+        let v = MalVal::vector(vec![MalVal::Atom(MalAtom::Int(1))]);
+        let with_meta = v.clone().with_meta(meta.clone()).unwrap();
+
+        assert_eq!(with_meta, v);
+        assert_eq!(with_meta.meta(), Some(&meta));
+        assert_eq!(v.meta(), None);
+        assert_eq!(with_meta.to_string(), "[1]");
+        assert_eq!(MalVal::Atom(MalAtom::Nil).with_meta(meta), None);
     }
 
     #[test]
@@ -205,11 +675,11 @@ mod tests {
             (MalVal::Atom(MalAtom::Nil), false),
             (MalVal::Atom(MalAtom::False), false),
             (MalVal::Atom(MalAtom::True), true),
-            (MalVal::Atom(MalAtom::Sym("some".to_owned())), true),
+            (MalVal::Atom(MalAtom::Sym("some".into())), true),
             (MalVal::Atom(MalAtom::Str("".to_owned())), true),
             (MalVal::Atom(MalAtom::Str("not-empty".to_owned())), true),
-            (MalVal::List(vec![]), true),
-            (MalVal::List(vec![MalVal::Atom(MalAtom::Nil)]), true),
+            (MalVal::list(vec![]), true),
+            (MalVal::list(vec![MalVal::Atom(MalAtom::Nil)]), true),
         ] {
             assert_eq!(v.is_truthy(), *expected, "{} = {}", v, expected);
         }
@@ -0,0 +1,315 @@
+use std::rc::Rc;
+
+use super::{
+    symbol::Symbol, EvalError, EvalResult, MalAtom, MalVal, Map, Meta, NativeFn, Regex, Seq, Set,
+};
+
+/// Conversion from a MAL value to a Rust value, failing if the value has
+/// the wrong type.
+pub trait FromMal: Sized {
+    fn from_mal(v: MalVal) -> EvalResult<Self>;
+}
+
+/// Conversion from a Rust value to a MAL value.
+pub trait IntoMal {
+    fn into_mal(self) -> MalVal;
+}
+
+fn unexpected(expected: &'static str, v: MalVal) -> EvalError {
+    EvalError::UnexpectedType(expected, v.to_string())
+}
+
+impl FromMal for MalVal {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        Ok(v)
+    }
+}
+
+impl FromMal for i64 {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::Atom(MalAtom::Int(i)) => Ok(i),
+            _ => Err(EvalError::NotANumber),
+        }
+    }
+}
+
+impl FromMal for bool {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::Atom(MalAtom::True) => Ok(true),
+            MalVal::Atom(MalAtom::False) => Ok(false),
+            v => Err(unexpected("a boolean", v)),
+        }
+    }
+}
+
+impl FromMal for String {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::Atom(MalAtom::Str(s)) => Ok(s),
+            v => Err(unexpected("a string", v)),
+        }
+    }
+}
+
+impl FromMal for char {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::Atom(MalAtom::Char(c)) => Ok(c),
+            v => Err(unexpected("a character", v)),
+        }
+    }
+}
+
+impl FromMal for Symbol {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::Atom(MalAtom::Sym(sym)) => Ok(sym),
+            _ => Err(EvalError::NotASymbol),
+        }
+    }
+}
+
+/// A list or vector.
+impl FromMal for Seq {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::List(seq, _) | MalVal::Vector(seq, _) => Ok(seq),
+            _ => Err(EvalError::NotAList),
+        }
+    }
+}
+
+impl FromMal for Map {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::AssocArray(map, _) => Ok(map),
+            v => Err(unexpected("a map", v)),
+        }
+    }
+}
+
+impl FromMal for Set {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::Set(set, _) => Ok(set),
+            v => Err(unexpected("a set", v)),
+        }
+    }
+}
+
+impl FromMal for Regex {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::Regex(re) => Ok(re),
+            v => Err(unexpected("a regex", v)),
+        }
+    }
+}
+
+/// Nil converts to `None`.
+impl<T: FromMal> FromMal for Option<T> {
+    fn from_mal(v: MalVal) -> EvalResult<Self> {
+        match v {
+            MalVal::Atom(MalAtom::Nil) => Ok(None),
+            v => T::from_mal(v).map(Some),
+        }
+    }
+}
+
+impl IntoMal for MalVal {
+    fn into_mal(self) -> MalVal {
+        self
+    }
+}
+
+impl IntoMal for i64 {
+    fn into_mal(self) -> MalVal {
+        MalVal::Atom(MalAtom::Int(self))
+    }
+}
+
+impl IntoMal for bool {
+    fn into_mal(self) -> MalVal {
+        MalVal::Atom(self.into())
+    }
+}
+
+impl IntoMal for String {
+    fn into_mal(self) -> MalVal {
+        MalVal::Atom(MalAtom::Str(self))
+    }
+}
+
+impl IntoMal for &str {
+    fn into_mal(self) -> MalVal {
+        MalVal::Atom(MalAtom::Str(self.to_owned()))
+    }
+}
+
+impl IntoMal for char {
+    fn into_mal(self) -> MalVal {
+        MalVal::Atom(MalAtom::Char(self))
+    }
+}
+
+impl IntoMal for Symbol {
+    fn into_mal(self) -> MalVal {
+        MalVal::Atom(MalAtom::Sym(self))
+    }
+}
+
+/// Converts to nil.
+impl IntoMal for () {
+    fn into_mal(self) -> MalVal {
+        MalVal::Atom(MalAtom::Nil)
+    }
+}
+
+/// Converts to a list.
+impl IntoMal for Seq {
+    fn into_mal(self) -> MalVal {
+        MalVal::List(self, Meta::default())
+    }
+}
+
+impl IntoMal for Map {
+    fn into_mal(self) -> MalVal {
+        MalVal::AssocArray(self, Meta::default())
+    }
+}
+
+impl IntoMal for Set {
+    fn into_mal(self) -> MalVal {
+        MalVal::Set(self, Meta::default())
+    }
+}
+
+impl IntoMal for Regex {
+    fn into_mal(self) -> MalVal {
+        MalVal::Regex(self)
+    }
+}
+
+/// Converts to a list.
+impl<T: IntoMal> IntoMal for Vec<T> {
+    fn into_mal(self) -> MalVal {
+        MalVal::List(self.into_iter().map(T::into_mal).collect(), Meta::default())
+    }
+}
+
+/// `None` converts to nil.
+impl<T: IntoMal> IntoMal for Option<T> {
+    fn into_mal(self) -> MalVal {
+        self.map_or(MalVal::Atom(MalAtom::Nil), T::into_mal)
+    }
+}
+
+/// Rust functions that can be registered as builtins. `Args` only tells
+/// the implementations apart.
+///
+/// Closures taking up to six arguments of `FromMal` types and returning an
+/// `EvalResult` of an `IntoMal` type check the number and types of their
+/// arguments before being called. Functions taking `Vec<MalVal>` receive
+/// the arguments unchecked, which suits builtins with a variable number of
+/// arguments.
+pub trait IntoNativeFn<Args> {
+    fn into_native_fn(self) -> NativeFn;
+}
+
+impl<F> IntoNativeFn<Vec<MalVal>> for F
+where
+    F: Fn(Vec<MalVal>) -> EvalResult<MalVal> + 'static,
+{
+    fn into_native_fn(self) -> NativeFn {
+        Rc::new(self)
+    }
+}
+
+macro_rules! impl_into_native_fn {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoNativeFn<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> EvalResult<R> + 'static,
+            R: IntoMal,
+            $($arg: FromMal,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_native_fn(self) -> NativeFn {
+                Rc::new(move |args: Vec<MalVal>| {
+                    if args.len() != <[&str]>::len(&[$(stringify!($arg)),*]) {
+                        return Err(EvalError::InvalidArgs);
+                    }
+                    let mut args = args.into_iter();
+                    $(let $arg = $arg::from_mal(args.next().unwrap())?;)*
+                    self($($arg),*).map(R::into_mal)
+                })
+            }
+        }
+    };
+}
+
+impl_into_native_fn!();
+impl_into_native_fn!(A);
+impl_into_native_fn!(A, B);
+impl_into_native_fn!(A, B, C);
+impl_into_native_fn!(A, B, C, D);
+impl_into_native_fn!(A, B, C, D, E);
+impl_into_native_fn!(A, B, C, D, E, G);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::test_util::{int, string};
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(i64::from_mal(int(3)), Ok(3));
+        assert_eq!(i64::from_mal(string("3")), Err(EvalError::NotANumber));
+        assert_eq!(String::from_mal(string("a")), Ok("a".to_owned()));
+        assert_eq!(
+            String::from_mal(int(1)),
+            Err(EvalError::UnexpectedType("a string", "1".to_owned()))
+        );
+        assert_eq!(
+            Option::<i64>::from_mal(MalVal::Atom(MalAtom::Nil)),
+            Ok(None)
+        );
+        assert_eq!(Option::<i64>::from_mal(int(1)), Ok(Some(1)));
+        assert_eq!(
+            Set::from_mal(int(1)),
+            Err(EvalError::UnexpectedType("a set", "1".to_owned()))
+        );
+
+        assert_eq!(vec![1, 2].into_mal().to_string(), "(1 2)");
+        assert_eq!(Some("a").into_mal(), string("a"));
+        assert_eq!(().into_mal(), MalVal::Atom(MalAtom::Nil));
+    }
+
+    #[test]
+    fn test_typed_fn() {
+        let f =
+            (|a: i64, b: String| -> EvalResult<bool> { Ok(b.len() as i64 == a) }).into_native_fn();
+        assert_eq!(
+            f(vec![int(1), string("a")]),
+            Ok(MalVal::Atom(MalAtom::True))
+        );
+        assert_eq!(f(vec![int(1)]), Err(EvalError::InvalidArgs));
+        assert_eq!(
+            f(vec![string("a"), string("a")]),
+            Err(EvalError::NotANumber)
+        );
+
+        let offset = 10;
+        let f = (move |a: i64| -> EvalResult<i64> { Ok(a + offset) }).into_native_fn();
+        assert_eq!(f(vec![int(1)]), Ok(int(11)));
+
+        let f = (|| -> EvalResult<()> { Ok(()) }).into_native_fn();
+        assert_eq!(f(vec![]), Ok(MalVal::Atom(MalAtom::Nil)));
+
+        let f = (|args: Vec<MalVal>| -> EvalResult<MalVal> { Ok(int(args.len() as i64)) })
+            .into_native_fn();
+        assert_eq!(f(vec![int(1), int(1), int(1)]), Ok(int(3)));
+    }
+}
@@ -0,0 +1,333 @@
+//! Conversion of MAL data to Rust values through serde.
+//!
+//! The reverse of `ser`: maps may be keyed by keywords or strings, any
+//! list or vector can fill a sequence, and an enum variant is read from a
+//! keyword or string naming it, or a single-entry map from its name to its
+//! contents.
+
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display},
+};
+
+use serde::{
+    de::{
+        self, value::MapDeserializer, value::SeqDeserializer, DeserializeOwned, IntoDeserializer,
+        Visitor,
+    },
+    forward_to_deserialize_any, Deserialize,
+};
+
+use super::{EvalError, EvalResult, MalAtom, MalVal, Map, Meta, Seq};
+
+impl de::Error for EvalError {
+    fn custom<T: Display>(msg: T) -> Self {
+        EvalError::Conversion(msg.to_string())
+    }
+}
+
+/// Converts MAL data to a `T`.
+pub fn from_mal<T: DeserializeOwned>(v: MalVal) -> EvalResult<T> {
+    T::deserialize(Deserializer(v))
+}
+
+/// Sequences deserialize as vectors. Strings stay strings, so map keys
+/// read from formats like JSON are strings as well.
+impl<'de> Deserialize<'de> for MalVal {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MalValVisitor)
+    }
+}
+
+struct MalValVisitor;
+
+impl<'de> Visitor<'de> for MalValVisitor {
+    type Value = MalVal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a MAL value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<MalVal, E> {
+        Ok(MalVal::Atom(v.into()))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<MalVal, E> {
+        Ok(MalVal::Atom(MalAtom::Int(v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<MalVal, E> {
+        match i64::try_from(v) {
+            Ok(v) => self.visit_i64(v),
+            Err(_) => Err(E::custom(format!("integer {} out of range", v))),
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<MalVal, E> {
+        Err(E::custom(format!("cannot represent float {}", v)))
+    }
+
+    fn visit_char<E: de::Error>(self, v: char) -> Result<MalVal, E> {
+        Ok(MalVal::Atom(MalAtom::Char(v)))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<MalVal, E> {
+        Ok(MalVal::Atom(MalAtom::Str(v.to_owned())))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<MalVal, E> {
+        Ok(MalVal::Atom(MalAtom::Str(v)))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<MalVal, E> {
+        Ok(MalVal::Atom(MalAtom::Nil))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<MalVal, E> {
+        self.visit_unit()
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<MalVal, D::Error> {
+        MalVal::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<MalVal, A::Error> {
+        let mut items = Seq::new();
+        while let Some(v) = seq.next_element()? {
+            items.push_back(v);
+        }
+        Ok(MalVal::Vector(items, Meta::default()))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<MalVal, A::Error> {
+        let mut entries = Map::default();
+        while let Some((k, v)) = map.next_entry()? {
+            entries.insert(k, v);
+        }
+        Ok(MalVal::AssocArray(entries, Meta::default()))
+    }
+}
+
+/// Deserializes a Rust value from the MAL value it wraps.
+pub struct Deserializer(MalVal);
+
+impl<'de> IntoDeserializer<'de, EvalError> for MalVal {
+    type Deserializer = Deserializer;
+
+    fn into_deserializer(self) -> Deserializer {
+        Deserializer(self)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = EvalError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> EvalResult<V::Value> {
+        match self.0 {
+            MalVal::Atom(MalAtom::Nil) => visitor.visit_unit(),
+            MalVal::Atom(MalAtom::True) => visitor.visit_bool(true),
+            MalVal::Atom(MalAtom::False) => visitor.visit_bool(false),
+            MalVal::Atom(MalAtom::Int(i)) => visitor.visit_i64(i),
+            MalVal::Atom(MalAtom::Str(s)) | MalVal::Atom(MalAtom::Keyword(s)) => {
+                visitor.visit_string(s)
+            }
+            MalVal::Atom(MalAtom::Sym(sym)) => visitor.visit_str(sym.as_str()),
+            MalVal::List(seq, _) | MalVal::Vector(seq, _) => {
+                let mut items = SeqDeserializer::new(seq.into_iter());
+                let v = visitor.visit_seq(&mut items)?;
+                items.end()?;
+                Ok(v)
+            }
+            MalVal::Atom(MalAtom::Char(c)) => visitor.visit_char(c),
+            MalVal::Set(set, _) => {
+                let mut items = SeqDeserializer::new(set.into_iter());
+                let v = visitor.visit_seq(&mut items)?;
+                items.end()?;
+                Ok(v)
+            }
+            MalVal::Tagged(t) => Deserializer(t.form.clone()).deserialize_any(visitor),
+            MalVal::Regex(re) => visitor.visit_str(re.0.as_str()),
+            MalVal::AssocArray(map, _) => {
+                let mut entries = MapDeserializer::new(map.into_iter());
+                let v = visitor.visit_map(&mut entries)?;
+                entries.end()?;
+                Ok(v)
+            }
+            MalVal::Fn(f) => Err(de::Error::custom(format!(
+                "cannot deserialize function {}",
+                f
+            ))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> EvalResult<V::Value> {
+        match self.0 {
+            MalVal::Atom(MalAtom::Nil) => visitor.visit_none(),
+            v => visitor.visit_some(Deserializer(v)),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> EvalResult<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> EvalResult<V::Value> {
+        let (variant, value) = match self.0 {
+            MalVal::AssocArray(map, _) if map.len() == 1 => {
+                let (k, v) = map.into_iter().next().unwrap();
+                (k, Some(v))
+            }
+            v => (v, None),
+        };
+        visitor.visit_enum(Enum { variant, value })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// An enum variant named by `variant`, with the contents in `value`.
+struct Enum {
+    variant: MalVal,
+    value: Option<MalVal>,
+}
+
+impl<'de> de::EnumAccess<'de> for Enum {
+    type Error = EvalError;
+    type Variant = Variant;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> EvalResult<(V::Value, Variant)> {
+        let variant = seed.deserialize(Deserializer(self.variant))?;
+        Ok((variant, Variant(self.value)))
+    }
+}
+
+struct Variant(Option<MalVal>);
+
+impl Variant {
+    fn value(self) -> EvalResult<MalVal> {
+        self.0
+            .ok_or_else(|| de::Error::custom("expected a map from the variant to its contents"))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant {
+    type Error = EvalError;
+
+    fn unit_variant(self) -> EvalResult<()> {
+        match self.0 {
+            None | Some(MalVal::Atom(MalAtom::Nil)) => Ok(()),
+            Some(v) => Err(de::Error::custom(format!("unexpected contents {}", v))),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> EvalResult<T::Value> {
+        seed.deserialize(Deserializer(self.value()?))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> EvalResult<V::Value> {
+        de::Deserializer::deserialize_any(Deserializer(self.value()?), visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> EvalResult<V::Value> {
+        de::Deserializer::deserialize_any(Deserializer(self.value()?), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reader::read_str, types::ser::to_mal};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    fn read(input: &str) -> MalVal {
+        read_str(input).unwrap().remove(0)
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Person {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+        email: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Empty,
+        Circle(i64),
+        Rect { w: i64, h: i64 },
+    }
+
+    #[test]
+    fn test_from_mal() {
+        let person: Person =
+            from_mal(read("{:name \"Ann\" :age 40 :tags (\"a\") :email nil}")).unwrap();
+        assert_eq!(
+            person,
+            Person {
+                name: "Ann".to_owned(),
+                age: 40,
+                tags: vec!["a".to_owned()],
+                email: None,
+            }
+        );
+        // String keys work as well as keywords.
+        let person: Person = from_mal(read(
+            "{\"name\" \"Ann\" \"age\" 40 \"tags\" [] \"email\" \"a@b\"}",
+        ))
+        .unwrap();
+        assert_eq!(person.email.as_deref(), Some("a@b"));
+
+        let map: HashMap<String, (i64, bool)> = from_mal(read("{\"k\" [1 true]}")).unwrap();
+        assert_eq!(map["k"], (1, true));
+
+        assert_eq!(from_mal::<Shape>(read(":Empty")).unwrap(), Shape::Empty);
+        assert_eq!(
+            from_mal::<Shape>(read("{:Circle 2}")).unwrap(),
+            Shape::Circle(2)
+        );
+        assert_eq!(
+            from_mal::<Shape>(read("{:Rect {:w 1 :h 2}}")).unwrap(),
+            Shape::Rect { w: 1, h: 2 }
+        );
+
+        assert!(matches!(
+            from_mal::<Person>(read("{:name 1}")),
+            Err(EvalError::Conversion(_))
+        ));
+        assert!(matches!(
+            from_mal::<u8>(read("300")),
+            Err(EvalError::Conversion(_))
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_malval() {
+        let v = read("(1 \"a\" nil [true {\"c\" 2}])");
+        assert_eq!(
+            from_mal::<MalVal>(v).unwrap(),
+            read("[1 \"a\" nil [true {\"c\" 2}]]")
+        );
+
+        let v = read("{\"a\" [1 2]}");
+        assert_eq!(from_mal::<MalVal>(to_mal(&v).unwrap()).unwrap(), v);
+    }
+}
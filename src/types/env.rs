@@ -1,15 +1,173 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug},
+    mem,
+    rc::{Rc, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use super::{MalVal, NativeFn};
+use crate::eval::{analyze, builtin};
 
-#[derive(Clone, Debug, PartialEq)]
+use super::{
+    convert::IntoNativeFn,
+    symbol::{Symbol, SymbolMap, SymbolSet},
+    EvalError, EvalResult, MalFn, MalVal, NativeFn, SpecialForm,
+};
+
+const MIN_PRUNE_AT: usize = 64;
+
+/// Default limit on nested calls to MAL functions. On the tree-walking
+/// backend each level costs about 12 KiB of native stack in debug builds
+/// and 3 KiB in release builds for a function whose body nests a few forms
+/// deep, and more for deeper bodies, so this fits in the 2 MiB that Rust
+/// gives spawned threads. Embedders running on a larger stack can raise it
+/// with `EnvironmentBuilder::with_max_depth`.
+pub const DEFAULT_MAX_DEPTH: usize = 100;
+
+/// A scope of variables. Environments compare by identity: two
+/// environments are equal only if they are the same scope.
+#[derive(Clone)]
 pub struct Environment(Rc<RefCell<EnvironmentInner>>);
 
-#[derive(Clone, Debug, PartialEq)]
 struct EnvironmentInner {
     parent: Option<Environment>,
-    builtin: HashMap<String, NativeFn>,
-    data: HashMap<String, MalVal>,
+    builtin: SymbolMap<NativeFn>,
+    /// Builtins whose result depends only on their arguments.
+    pure: SymbolSet,
+    data: SymbolMap<MalVal>,
+    /// Local variables, addressed by slot. `names` holds the name of each
+    /// slot for lookups by name. A slot is `None` until it is assigned.
+    names: Rc<[Symbol]>,
+    slots: Vec<Option<MalVal>>,
+    state: Rc<EvalState>,
+    /// Whether the environment is in `EvalState::envs`.
+    tracked: bool,
+}
+
+/// Evaluation state shared by a root environment and all environments
+/// derived from it.
+#[derive(Debug)]
+struct EvalState {
+    max_depth: Option<usize>,
+    depth: Cell<usize>,
+    step_limit: Option<u64>,
+    time_limit: Option<Duration>,
+    steps: Cell<u64>,
+    deadline: Cell<Option<Instant>>,
+    interrupt: Option<InterruptFlag>,
+    special_forms: SymbolMap<analyze::Form>,
+    backend: Backend,
+    optimize: bool,
+    /// Environments that may be part of a reference cycle, see
+    /// `Environment::collect_cycles`.
+    envs: RefCell<Vec<Weak<RefCell<EnvironmentInner>>>>,
+    /// Size of `envs` at which dropped environments are next pruned.
+    prune_at: Cell<usize>,
+}
+
+/// How `eval::eval` evaluates forms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Walk the analyzed form directly.
+    #[default]
+    TreeWalk,
+    /// Compile the analyzed form to bytecode and run it on `eval::vm`.
+    Bytecode,
+}
+
+/// A flag that can be raised from another thread or a signal handler to
+/// cancel the evaluation currently running in an environment.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptFlag(Arc<AtomicBool>);
+
+impl InterruptFlag {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn clear(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    fn take(&self) -> bool {
+        // Checked on every evaluation step, so avoid the swap unless set.
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl PartialEq for InterruptFlag {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Tracks one level of nested evaluation. The depth is released when the
+/// guard is dropped.
+#[derive(Debug)]
+pub struct DepthGuard {
+    state: Rc<EvalState>,
+}
+
+/// The limits of an environment tree. Evaluators consume a step for each
+/// unit of work and descend a level for each call to a MAL function.
+#[derive(Clone, Debug)]
+pub struct Budget(Rc<EvalState>);
+
+impl Budget {
+    /// Consumes one step. Fails once the evaluation was interrupted or the
+    /// step limit or deadline would be exceeded.
+    pub fn step(&self) -> EvalResult<()> {
+        let state = &self.0;
+        if let Some(interrupt) = &state.interrupt {
+            if interrupt.take() {
+                return Err(EvalError::Interrupted);
+            }
+        }
+        let steps = state.steps.get() + 1;
+        if let Some(step_limit) = state.step_limit {
+            if steps > step_limit {
+                return Err(EvalError::StepLimitExceeded(step_limit));
+            }
+        }
+        if let Some(deadline) = state.deadline.get() {
+            if Instant::now() >= deadline {
+                return Err(EvalError::DeadlineExceeded);
+            }
+        }
+        state.steps.set(steps);
+        Ok(())
+    }
+
+    /// Enters one level of nesting. Fails once the maximum depth would be
+    /// exceeded.
+    pub fn descend(&self) -> EvalResult<DepthGuard> {
+        let state = &self.0;
+        let depth = state.depth.get() + 1;
+        if let Some(max_depth) = state.max_depth {
+            if depth > max_depth {
+                return Err(EvalError::StackOverflow(depth));
+            }
+        }
+        state.depth.set(depth);
+        Ok(DepthGuard {
+            state: state.clone(),
+        })
+    }
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        self.state.depth.set(self.state.depth.get() - 1);
+    }
 }
 
 #[derive(Clone)]
@@ -19,66 +177,487 @@ pub enum EnvVal {
 }
 
 pub struct EnvironmentBuilder {
-    env: EnvironmentInner,
+    parent: Option<Environment>,
+    builtin: SymbolMap<NativeFn>,
+    pure: SymbolSet,
+    names: Rc<[Symbol]>,
+    slots: Vec<Option<MalVal>>,
+    max_depth: Option<usize>,
+    step_limit: Option<u64>,
+    time_limit: Option<Duration>,
+    interrupt: Option<InterruptFlag>,
+    special_forms: SymbolMap<SpecialForm>,
+    backend: Backend,
+    optimize: bool,
+    io: bool,
 }
 
 impl EnvironmentBuilder {
     pub fn new() -> Self {
         EnvironmentBuilder {
-            env: EnvironmentInner {
-                parent: None,
-                builtin: HashMap::new(),
-                data: HashMap::new(),
-            },
+            parent: None,
+            builtin: SymbolMap::default(),
+            pure: SymbolSet::default(),
+            names: Rc::new([]),
+            slots: Vec::new(),
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+            step_limit: None,
+            time_limit: None,
+            interrupt: None,
+            special_forms: SymbolMap::default(),
+            backend: Backend::default(),
+            optimize: false,
+            io: false,
         }
     }
 
+    /// Child environments share the evaluation state of their parent: its
+    /// limits, interrupt flag, special forms, backend and optimization
+    /// setting. Setting any of these on a child builder has no effect.
     pub fn with_parent(mut self, env: &Environment) -> Self {
-        self.env.parent = Some(env.clone());
+        self.parent = Some(env.clone());
+        self
+    }
+
+    /// Sets how deeply calls to MAL functions may nest before failing with
+    /// `EvalError::StackOverflow`. `None` removes the limit. Only takes
+    /// effect on a root environment, see `with_parent`.
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets how many evaluation steps may be taken per budget before
+    /// failing with `EvalError::StepLimitExceeded`. See
+    /// `Environment::reset_budget`.
+    pub fn with_step_limit(mut self, step_limit: Option<u64>) -> Self {
+        self.step_limit = step_limit;
+        self
+    }
+
+    /// Sets how much wall-clock time may be spent per budget before
+    /// failing with `EvalError::DeadlineExceeded`. See
+    /// `Environment::reset_budget`.
+    pub fn with_time_limit(mut self, time_limit: Option<Duration>) -> Self {
+        self.time_limit = time_limit;
+        self
+    }
+
+    /// Makes evaluations fail with `EvalError::Interrupted` once `flag` is
+    /// raised. The flag is cleared when the interruption is reported.
+    pub fn with_interrupt(mut self, flag: InterruptFlag) -> Self {
+        self.interrupt = Some(flag);
+        self
+    }
+
+    /// Registers special forms, which replace standard forms of the same
+    /// name. Like the limits these are shared by the whole
+    /// environment tree, so they only take effect on a root environment.
+    pub fn with_special_forms(mut self, forms: HashMap<String, SpecialForm>) -> Self {
+        for (sym_name, form) in forms {
+            self.special_forms.insert(sym_name.into(), form);
+        }
+        self
+    }
+
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Folds calls to pure builtins with constant arguments and prunes
+    /// `if` branches that can never be taken before evaluating a form.
+    pub fn with_optimize(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Registers the builtins that read and write files, environment
+    /// variables and subprocesses, see `builtin::io`. Without this,
+    /// evaluation has no effects outside the interpreter.
+    pub fn with_io(mut self, io: bool) -> Self {
+        self.io = io;
+        self
+    }
+
+    /// Gives the environment a frame of local variables named `names`.
+    /// Slots without a value in `slots` are unset until assigned, see
+    /// `Environment::get_local`.
+    pub fn with_frame(mut self, names: Rc<[Symbol]>, slots: Vec<MalVal>) -> Self {
+        self.slots = slots.into_iter().map(Some).collect();
+        self.slots.resize(names.len(), None);
+        self.names = names;
         self
     }
 
     pub fn with_builtins(mut self, fs: HashMap<String, NativeFn>) -> Self {
         for (sym_name, f) in fs {
-            self.env.builtin.insert(sym_name, f);
+            self.builtin.insert(sym_name.into(), f);
+        }
+        self
+    }
+
+    /// Registers the builtins in `fs` whose names are still free, so that
+    /// builtins registered earlier take precedence. Of those added, the
+    /// ones named in `pure` are marked pure, see `with_pure_builtins`.
+    pub fn with_default_builtins(
+        mut self,
+        fs: HashMap<String, NativeFn>,
+        pure: HashSet<String>,
+    ) -> Self {
+        for (sym_name, f) in fs {
+            let sym = Symbol::from(sym_name.as_str());
+            if self.builtin.contains_key(&sym) {
+                continue;
+            }
+            self.builtin.insert(sym, f);
+            if pure.contains(&sym_name) {
+                self.pure.insert(sym);
+            }
         }
         self
     }
 
-    pub fn build(self) -> Environment {
-        Environment(Rc::new(RefCell::new(self.env)))
+    /// Registers a Rust function or closure as the builtin `name`, e.g.
+    /// `with_fn("repeat", |s: String, n: i64| -> EvalResult<String> { .. })`.
+    /// See `IntoNativeFn`.
+    pub fn with_fn<Args, F: IntoNativeFn<Args>>(mut self, name: &str, f: F) -> Self {
+        self.builtin.insert(name.into(), f.into_native_fn());
+        self
+    }
+
+    /// Marks the builtins named in `names` as pure: given the same
+    /// arguments they always return the same value and have no side
+    /// effects, so the optimizer may call them ahead of time.
+    pub fn with_pure_builtins(mut self, names: HashSet<String>) -> Self {
+        self.pure.extend(names.into_iter().map(Symbol::from));
+        self
+    }
+
+    pub fn build(mut self) -> Environment {
+        if self.io {
+            // Builtins registered under the same name take precedence.
+            for (sym_name, f) in builtin::io() {
+                self.builtin.entry(sym_name.into()).or_insert(f);
+            }
+        }
+        let state = match &self.parent {
+            Some(parent) => parent.0.borrow().state.clone(),
+            None => {
+                let mut special_forms: SymbolMap<analyze::Form> = analyze::special_forms()
+                    .into_iter()
+                    .map(|(sym_name, form)| (sym_name.into(), form))
+                    .collect();
+                special_forms.extend(
+                    self.special_forms
+                        .into_iter()
+                        .map(|(sym, form)| (sym, analyze::Form::Custom(form))),
+                );
+                Rc::new(EvalState {
+                    max_depth: self.max_depth,
+                    depth: Cell::new(0),
+                    step_limit: self.step_limit,
+                    time_limit: self.time_limit,
+                    steps: Cell::new(0),
+                    deadline: Cell::new(None),
+                    interrupt: self.interrupt,
+                    special_forms,
+                    backend: self.backend,
+                    optimize: self.optimize,
+                    envs: RefCell::new(Vec::new()),
+                    prune_at: Cell::new(MIN_PRUNE_AT),
+                })
+            }
+        };
+        let env = Environment(Rc::new(RefCell::new(EnvironmentInner {
+            parent: self.parent,
+            builtin: self.builtin,
+            pure: self.pure,
+            data: SymbolMap::default(),
+            names: self.names,
+            slots: self.slots,
+            state,
+            tracked: false,
+        })));
+        if env.0.borrow().parent.is_none() {
+            env.reset_budget();
+        }
+        env
+    }
+}
+
+impl Default for EnvironmentBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartialEq for Environment {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Debug for Environment {
+    // Only the names are shown: values may be closures over this very
+    // environment.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let env = self.0.borrow();
+        f.debug_struct("Environment")
+            .field("names", &env.names)
+            .field("data", &env.data.keys().collect::<Vec<_>>())
+            .field("parent", &env.parent.as_ref().map(|p| Rc::as_ptr(&p.0)))
+            .finish_non_exhaustive()
     }
 }
 
 impl Environment {
-    pub fn set(&self, sym_name: String, val: MalVal) {
-        self.0.borrow_mut().data.insert(sym_name, val);
-    }
-
-    pub fn get(&self, sym_name: &str) -> Option<EnvVal> {
-        self.find(sym_name).map(|e| {
-            let env = e.0.borrow();
-            if env.builtin.contains_key(sym_name) {
-                let f = env.builtin[sym_name];
-                EnvVal::NativeFn(f)
-            } else if env.data.contains_key(sym_name) {
-                let v = env.data[sym_name].clone();
-                EnvVal::Val(v)
-            } else {
-                unreachable!()
-            }
-        })
+    /// Starts a new evaluation budget: the step count is reset, the
+    /// deadline is moved to now plus the configured time limit and any
+    /// stale interruption is discarded. Embedders typically call this
+    /// before each top-level evaluation.
+    pub fn reset_budget(&self) {
+        let state = &self.0.borrow().state;
+        if let Some(interrupt) = &state.interrupt {
+            interrupt.clear();
+        }
+        state.steps.set(0);
+        state
+            .deadline
+            .set(state.time_limit.map(|limit| Instant::now() + limit));
+    }
+
+    /// Consumes one step of the budget, see `Budget::step`.
+    pub fn step(&self) -> EvalResult<()> {
+        self.budget().step()
+    }
+
+    pub fn budget(&self) -> Budget {
+        Budget(self.0.borrow().state.clone())
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.0.borrow().state.backend
+    }
+
+    pub fn optimize(&self) -> bool {
+        self.0.borrow().state.optimize
+    }
+
+    pub fn parent(&self) -> Option<Environment> {
+        self.0.borrow().parent.clone()
+    }
+
+    /// The special form named `sym`, standard or registered by an
+    /// embedder.
+    pub fn special_form(&self, sym: Symbol) -> Option<analyze::Form> {
+        self.0.borrow().state.special_forms.get(&sym).copied()
+    }
+
+    pub fn set(&self, sym: Symbol, val: MalVal) {
+        self.0.borrow_mut().data.insert(sym, val);
     }
 
-    pub fn find(&self, sym_name: &str) -> Option<Environment> {
-        if self.0.borrow().data.contains_key(sym_name)
-            || self.0.borrow().builtin.contains_key(sym_name)
-        {
-            Some(Environment(self.0.clone()))
-        } else if let Some(parent) = &self.0.borrow().parent {
-            parent.find(sym_name)
+    /// Defines the builtin `sym` in this environment.
+    pub fn set_builtin(&self, sym: Symbol, f: NativeFn) {
+        self.0.borrow_mut().builtin.insert(sym, f);
+    }
+
+    /// Looks up `sym` by name, including local variables that have been
+    /// assigned.
+    pub fn get(&self, sym: Symbol) -> Option<EnvVal> {
+        let env = self.0.borrow();
+        let local = env
+            .names
+            .iter()
+            .zip(&env.slots)
+            .rev()
+            .find_map(|(&name, v)| match v {
+                Some(v) if name == sym => Some(v),
+                _ => None,
+            });
+        if let Some(v) = local {
+            Some(EnvVal::Val(v.clone()))
+        } else if let Some(f) = env.builtin.get(&sym) {
+            Some(EnvVal::NativeFn(f.clone()))
+        } else if let Some(v) = env.data.get(&sym) {
+            Some(EnvVal::Val(v.clone()))
+        } else if let Some(parent) = &env.parent {
+            parent.get(sym)
+        } else {
+            None
+        }
+    }
+
+    /// Looks up `sym` among builtins and `def!`-ed values only. Local
+    /// variables are resolved ahead of time and read with `get_local`.
+    pub fn get_global(&self, sym: Symbol) -> Option<EnvVal> {
+        let env = self.0.borrow();
+        if let Some(f) = env.builtin.get(&sym) {
+            Some(EnvVal::NativeFn(f.clone()))
+        } else if let Some(v) = env.data.get(&sym) {
+            Some(EnvVal::Val(v.clone()))
+        } else if let Some(parent) = &env.parent {
+            parent.get_global(sym)
         } else {
             None
         }
     }
+
+    /// Whether `sym` refers to a builtin marked pure, as opposed to an
+    /// impure builtin or a value.
+    pub fn is_pure_builtin(&self, sym: Symbol) -> bool {
+        let env = self.0.borrow();
+        if env.builtin.contains_key(&sym) {
+            env.pure.contains(&sym)
+        } else if env.data.contains_key(&sym) {
+            false
+        } else if let Some(parent) = &env.parent {
+            parent.is_pure_builtin(sym)
+        } else {
+            false
+        }
+    }
+
+    /// Reads slot `slot` of the frame `depth` levels up from this one. If
+    /// the slot is unset, e.g. because the `def!` that assigns it has not
+    /// run yet, its name is returned instead so that the caller can look
+    /// the variable up by name.
+    pub fn get_local(&self, depth: usize, slot: usize) -> Result<MalVal, Symbol> {
+        let env = self.0.borrow();
+        match (depth, &env.parent) {
+            (0, _) => env.slots[slot].clone().ok_or(env.names[slot]),
+            (_, Some(parent)) => parent.get_local(depth - 1, slot),
+            (_, None) => panic!("local variable resolved beyond the root environment"),
+        }
+    }
+
+    pub fn set_local(&self, slot: usize, val: MalVal) {
+        self.0.borrow_mut().slots[slot] = Some(val);
+    }
+
+    /// The slot names of every frame from the root environment down to
+    /// this one.
+    pub fn scope(&self) -> Vec<Rc<[Symbol]>> {
+        let env = self.0.borrow();
+        let mut scope = match &env.parent {
+            Some(parent) => parent.scope(),
+            None => Vec::new(),
+        };
+        scope.push(env.names.clone());
+        scope
+    }
+
+    /// Registers this environment and its parents with the cycle
+    /// collector. Called when a closure captures the environment, which is
+    /// the only way for a value stored in an environment to refer back to
+    /// it.
+    pub fn track(&self) {
+        let mut env = self.clone();
+        loop {
+            let parent = {
+                let mut inner = env.0.borrow_mut();
+                if inner.tracked {
+                    return;
+                }
+                inner.tracked = true;
+                let state = &inner.state;
+                let mut envs = state.envs.borrow_mut();
+                envs.push(Rc::downgrade(&env.0));
+                if envs.len() >= state.prune_at.get() {
+                    envs.retain(|env| env.strong_count() > 0);
+                    state.prune_at.set((envs.len() * 2).max(MIN_PRUNE_AT));
+                }
+                inner.parent.clone()
+            };
+            match parent {
+                Some(parent) => env = parent,
+                None => return,
+            }
+        }
+    }
+
+    /// Frees environments that are only kept alive by reference cycles,
+    /// typically a function stored in the environment it closes over, and
+    /// returns how many were freed.
+    ///
+    /// Reference counts of tracked environments are compared against the
+    /// references from other tracked environments: parent links, and
+    /// closures held directly in a variable whose every reference is such
+    /// a variable. Environments with references beyond those, and
+    /// everything they reach, are live. Closures held anywhere else, such
+    /// as inside a collection or by Rust code, are conservatively treated
+    /// as live too, so it is safe to collect at any time.
+    pub fn collect_cycles(&self) -> usize {
+        let state = self.0.borrow().state.clone();
+        let envs: Vec<_> = {
+            let mut envs = state.envs.borrow_mut();
+            envs.retain(|env| env.strong_count() > 0);
+            envs.iter().filter_map(Weak::upgrade).collect()
+        };
+        let index: HashMap<_, _> = envs
+            .iter()
+            .enumerate()
+            .map(|(i, env)| (Rc::as_ptr(env), i))
+            .collect();
+        let find = |env: &Environment| index.get(&Rc::as_ptr(&env.0)).copied();
+
+        // References not explained by other tracked environments. The
+        // upgraded pointers in `envs` account for one of each.
+        let mut external: Vec<_> = envs.iter().map(|env| Rc::strong_count(env) - 1).collect();
+        let mut closures: HashMap<*const MalFn, (Rc<MalFn>, usize)> = HashMap::new();
+        for env in &envs {
+            let env = env.borrow();
+            if let Some(i) = env.parent.as_ref().and_then(find) {
+                external[i] -= 1;
+            }
+            for f in direct_closures(&env) {
+                closures.entry(Rc::as_ptr(f)).or_insert((f.clone(), 0)).1 += 1;
+            }
+        }
+        for (f, held) in closures.values() {
+            // The map itself holds one reference.
+            if Rc::strong_count(f) - 1 == *held {
+                if let Some(i) = find(&f.env) {
+                    external[i] -= 1;
+                }
+            }
+        }
+        drop(closures);
+
+        let mut live = vec![false; envs.len()];
+        let mut pending: Vec<_> = (0..envs.len()).filter(|&i| external[i] > 0).collect();
+        while let Some(i) = pending.pop() {
+            if mem::replace(&mut live[i], true) {
+                continue;
+            }
+            let env = envs[i].borrow();
+            pending.extend(env.parent.as_ref().and_then(find));
+            pending.extend(direct_closures(&env).filter_map(|f| find(&f.env)));
+        }
+
+        // Emptying the garbage environments breaks the cycles. The
+        // contents are dropped only once no environment is borrowed.
+        let mut garbage = Vec::new();
+        for (env, _) in envs.iter().zip(live).filter(|(_, live)| !live) {
+            let mut env = env.borrow_mut();
+            garbage.push((
+                env.parent.take(),
+                mem::take(&mut env.data),
+                mem::take(&mut env.slots),
+            ));
+        }
+        garbage.len()
+    }
+}
+
+/// Closures held directly in the variables of `env`.
+fn direct_closures(env: &EnvironmentInner) -> impl Iterator<Item = &Rc<MalFn>> {
+    env.data
+        .values()
+        .chain(env.slots.iter().flatten())
+        .filter_map(|v| match v {
+            MalVal::Fn(f) => Some(f),
+            _ => None,
+        })
 }
@@ -0,0 +1,461 @@
+//! Conversion of Rust values to MAL data through serde.
+//!
+//! Structs become maps keyed by keywords named after their fields, other
+//! maps keep their keys as they are, and sequences and tuples become
+//! vectors. Enum variants become a keyword, or a single-entry map from the
+//! keyword to their contents. Floats cannot be represented and fail to
+//! convert.
+
+use std::{convert::TryFrom, fmt::Display};
+
+use serde::{ser, Serialize};
+
+use super::{EvalError, EvalResult, MalAtom, MalVal, Map, Meta, Seq};
+
+impl ser::Error for EvalError {
+    fn custom<T: Display>(msg: T) -> Self {
+        EvalError::Conversion(msg.to_string())
+    }
+}
+
+/// Converts `value` to MAL data.
+pub fn to_mal<T: Serialize + ?Sized>(value: &T) -> EvalResult<MalVal> {
+    value.serialize(Serializer)
+}
+
+/// Keywords and symbols serialize as their name, so that maps read from
+/// MAL source with keyword keys serialize like the corresponding struct.
+/// Sets serialize as sequences, tagged literals as their form and regexes
+/// as their source.
+impl Serialize for MalVal {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{Error, SerializeMap, SerializeSeq};
+        match self {
+            MalVal::Atom(MalAtom::Nil) => serializer.serialize_unit(),
+            MalVal::Atom(MalAtom::True) => serializer.serialize_bool(true),
+            MalVal::Atom(MalAtom::False) => serializer.serialize_bool(false),
+            MalVal::Atom(MalAtom::Int(i)) => serializer.serialize_i64(*i),
+            MalVal::Atom(MalAtom::Str(s)) | MalVal::Atom(MalAtom::Keyword(s)) => {
+                serializer.serialize_str(s)
+            }
+            MalVal::Atom(MalAtom::Sym(sym)) => serializer.serialize_str(sym.as_str()),
+            MalVal::List(seq, _) | MalVal::Vector(seq, _) => {
+                let mut s = serializer.serialize_seq(Some(seq.len()))?;
+                for v in seq {
+                    s.serialize_element(v)?;
+                }
+                s.end()
+            }
+            MalVal::AssocArray(map, _) => {
+                let mut s = serializer.serialize_map(Some(map.len()))?;
+                for (k, v) in map {
+                    s.serialize_entry(k, v)?;
+                }
+                s.end()
+            }
+            MalVal::Atom(MalAtom::Char(c)) => serializer.serialize_char(*c),
+            MalVal::Set(set, _) => {
+                let mut s = serializer.serialize_seq(Some(set.len()))?;
+                for v in set {
+                    s.serialize_element(v)?;
+                }
+                s.end()
+            }
+            MalVal::Tagged(t) => t.form.serialize(serializer),
+            MalVal::Regex(re) => serializer.serialize_str(re.0.as_str()),
+            MalVal::Fn(f) => Err(S::Error::custom(format!("cannot serialize function {}", f))),
+        }
+    }
+}
+
+struct Serializer;
+
+fn keyword(name: &str) -> MalVal {
+    MalVal::Atom(MalAtom::Keyword(name.to_owned()))
+}
+
+/// A single-entry map from the variant name to `value`.
+fn variant(name: &str, value: MalVal) -> MalVal {
+    let mut map = Map::default();
+    map.insert(keyword(name), value);
+    MalVal::AssocArray(map, Meta::default())
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = MalVal;
+    type Error = EvalError;
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeVec;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeMap;
+
+    fn serialize_bool(self, v: bool) -> EvalResult<MalVal> {
+        Ok(MalVal::Atom(v.into()))
+    }
+
+    fn serialize_i8(self, v: i8) -> EvalResult<MalVal> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> EvalResult<MalVal> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> EvalResult<MalVal> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> EvalResult<MalVal> {
+        Ok(MalVal::Atom(MalAtom::Int(v)))
+    }
+
+    fn serialize_u8(self, v: u8) -> EvalResult<MalVal> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> EvalResult<MalVal> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> EvalResult<MalVal> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> EvalResult<MalVal> {
+        match i64::try_from(v) {
+            Ok(v) => self.serialize_i64(v),
+            Err(_) => Err(ser::Error::custom(format!("integer {} out of range", v))),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> EvalResult<MalVal> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> EvalResult<MalVal> {
+        Err(ser::Error::custom(format!("cannot represent float {}", v)))
+    }
+
+    fn serialize_char(self, v: char) -> EvalResult<MalVal> {
+        Ok(MalVal::Atom(MalAtom::Char(v)))
+    }
+
+    fn serialize_str(self, v: &str) -> EvalResult<MalVal> {
+        Ok(MalVal::Atom(MalAtom::Str(v.to_owned())))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> EvalResult<MalVal> {
+        let bytes = v.iter().map(|&b| MalVal::Atom(MalAtom::Int(b.into())));
+        Ok(MalVal::Vector(bytes.collect(), Meta::default()))
+    }
+
+    fn serialize_none(self) -> EvalResult<MalVal> {
+        Ok(MalVal::Atom(MalAtom::Nil))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> EvalResult<MalVal> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> EvalResult<MalVal> {
+        Ok(MalVal::Atom(MalAtom::Nil))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> EvalResult<MalVal> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> EvalResult<MalVal> {
+        Ok(keyword(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> EvalResult<MalVal> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        value: &T,
+    ) -> EvalResult<MalVal> {
+        Ok(variant(name, to_mal(value)?))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> EvalResult<SerializeVec> {
+        Ok(SerializeVec {
+            variant: None,
+            items: Seq::new(),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> EvalResult<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> EvalResult<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> EvalResult<SerializeVec> {
+        Ok(SerializeVec {
+            variant: Some(variant),
+            items: Seq::new(),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> EvalResult<SerializeMap> {
+        Ok(SerializeMap {
+            variant: None,
+            map: Map::default(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> EvalResult<SerializeMap> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> EvalResult<SerializeMap> {
+        Ok(SerializeMap {
+            variant: Some(variant),
+            map: Map::default(),
+            key: None,
+        })
+    }
+}
+
+/// Builds a vector, wrapped in a variant map for tuple variants.
+struct SerializeVec {
+    variant: Option<&'static str>,
+    items: Seq,
+}
+
+impl SerializeVec {
+    fn finish(self) -> MalVal {
+        let v = MalVal::Vector(self.items, Meta::default());
+        match self.variant {
+            Some(name) => variant(name, v),
+            None => v,
+        }
+    }
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = MalVal;
+    type Error = EvalError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> EvalResult<()> {
+        self.items.push_back(to_mal(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> EvalResult<MalVal> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = MalVal;
+    type Error = EvalError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> EvalResult<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> EvalResult<MalVal> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = MalVal;
+    type Error = EvalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> EvalResult<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> EvalResult<MalVal> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVec {
+    type Ok = MalVal;
+    type Error = EvalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> EvalResult<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> EvalResult<MalVal> {
+        Ok(self.finish())
+    }
+}
+
+/// Builds a map, wrapped in a variant map for struct variants. Struct
+/// fields are keyed by keywords.
+struct SerializeMap {
+    variant: Option<&'static str>,
+    map: Map,
+    key: Option<MalVal>,
+}
+
+impl SerializeMap {
+    fn finish(self) -> MalVal {
+        let v = MalVal::AssocArray(self.map, Meta::default());
+        match self.variant {
+            Some(name) => variant(name, v),
+            None => v,
+        }
+    }
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = MalVal;
+    type Error = EvalError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> EvalResult<()> {
+        self.key = Some(to_mal(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> EvalResult<()> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.map.insert(key, to_mal(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> EvalResult<MalVal> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = MalVal;
+    type Error = EvalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> EvalResult<()> {
+        self.map.insert(keyword(key), to_mal(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> EvalResult<MalVal> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeMap {
+    type Ok = MalVal;
+    type Error = EvalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> EvalResult<()> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> EvalResult<MalVal> {
+        Ok(self.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::read_str;
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    fn read(input: &str) -> MalVal {
+        read_str(input).unwrap().remove(0)
+    }
+
+    #[derive(Serialize)]
+    struct Person {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+        email: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    enum Shape {
+        Empty,
+        Circle(i64),
+        Rect { w: i64, h: i64 },
+    }
+
+    #[test]
+    fn test_to_mal() {
+        let person = Person {
+            name: "Ann".to_owned(),
+            age: 40,
+            tags: vec!["a".to_owned()],
+            email: None,
+        };
+        assert_eq!(
+            to_mal(&person).unwrap(),
+            read("{:name \"Ann\" :age 40 :tags [\"a\"] :email nil}")
+        );
+
+        let mut map = BTreeMap::new();
+        map.insert("k", (1, true));
+        assert_eq!(to_mal(&map).unwrap(), read("{\"k\" [1 true]}"));
+
+        assert_eq!(to_mal(&Shape::Empty).unwrap(), read(":Empty"));
+        assert_eq!(to_mal(&Shape::Circle(2)).unwrap(), read("{:Circle 2}"));
+        assert_eq!(
+            to_mal(&Shape::Rect { w: 1, h: 2 }).unwrap(),
+            read("{:Rect {:w 1 :h 2}}")
+        );
+
+        assert!(matches!(to_mal(&1.5), Err(EvalError::Conversion(_))));
+        assert!(matches!(to_mal(&u64::MAX), Err(EvalError::Conversion(_))));
+    }
+
+    #[test]
+    fn test_serialize_malval() {
+        // Keywords serialize as their name, like struct fields.
+        let v = read("(1 \"a\" :b nil [true {:c 2}])");
+        assert_eq!(
+            to_mal(&v).unwrap(),
+            read("[1 \"a\" \"b\" nil [true {\"c\" 2}]]")
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Display},
+    hash::{BuildHasherDefault, Hasher},
+    sync::{Mutex, OnceLock},
+};
+
+/// An interned symbol name. Symbols compare and hash by id, so looking one
+/// up never touches the underlying string.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    pub const DEF: Symbol = Symbol(0);
+    pub const LET: Symbol = Symbol(1);
+    pub const FN: Symbol = Symbol(2);
+    pub const DO: Symbol = Symbol(3);
+    pub const IF: Symbol = Symbol(4);
+    pub const LOOP: Symbol = Symbol(5);
+    pub const RECUR: Symbol = Symbol(6);
+    pub const WITH_META: Symbol = Symbol(7);
+
+    pub fn new(name: &str) -> Self {
+        interner().lock().unwrap().intern(name)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        interner().lock().unwrap().names[self.0 as usize]
+    }
+}
+
+/// Names of the predefined symbols, indexed by id.
+const PREDEFINED: [&str; 8] = [
+    "def!",
+    "let*",
+    "fn*",
+    "do",
+    "if",
+    "loop",
+    "recur",
+    "with-meta",
+];
+
+/// Symbol names are leaked on first use; the set of distinct symbols in a
+/// program is small and they live as long as the process anyway.
+struct Interner {
+    ids: HashMap<&'static str, Symbol>,
+    names: Vec<&'static str>,
+}
+
+impl Interner {
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(&sym) = self.ids.get(name) {
+            return sym;
+        }
+        let name: &'static str = Box::leak(name.to_owned().into_boxed_str());
+        let sym = Symbol(self.names.len() as u32);
+        self.names.push(name);
+        self.ids.insert(name, sym);
+        sym
+    }
+}
+
+fn interner() -> &'static Mutex<Interner> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(|| {
+        let mut interner = Interner {
+            ids: HashMap::new(),
+            names: Vec::new(),
+        };
+        for name in PREDEFINED {
+            interner.intern(name);
+        }
+        Mutex::new(interner)
+    })
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::new(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::new(&name)
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Hash map keyed by symbol. Ids are already unique, so hashing only has
+/// to spread them across the full width of the hash.
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+pub type SymbolSet = HashSet<Symbol, BuildHasherDefault<SymbolHasher>>;
+
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 << 8) | u64::from(b);
+        }
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.0 = u64::from(i).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predefined() {
+        for (i, name) in PREDEFINED.iter().enumerate() {
+            assert_eq!(Symbol::from(*name), Symbol(i as u32));
+        }
+        assert_eq!(Symbol::DEF.as_str(), "def!");
+        assert_eq!(Symbol::WITH_META.as_str(), "with-meta");
+    }
+
+    #[test]
+    fn test_intern() {
+        let a = Symbol::from("some-symbol");
+        assert_eq!(a, Symbol::from("some-symbol".to_owned()));
+        assert_ne!(a, Symbol::from("other-symbol"));
+        assert_eq!(a.as_str(), "some-symbol");
+        assert_eq!(a.to_string(), "some-symbol");
+    }
+}
@@ -0,0 +1,15 @@
+//! Shorthands for building values in tests.
+
+use super::{MalAtom, MalVal};
+
+pub fn int(i: i64) -> MalVal {
+    MalVal::Atom(MalAtom::Int(i))
+}
+
+pub fn string(s: &str) -> MalVal {
+    MalVal::Atom(MalAtom::Str(s.to_owned()))
+}
+
+pub fn kw(k: &str) -> MalVal {
+    MalVal::Atom(MalAtom::Keyword(k.to_owned()))
+}